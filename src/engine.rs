@@ -0,0 +1,415 @@
+use crate::envelope::EnvelopeParams;
+use crate::filter::FilterParams;
+use crate::fm::FmParams;
+use crate::lfo::{LfoParams, LFO_COUNT};
+use crate::limiter::LimiterParams;
+use crate::modulation::{ModDestination, ModInputs, ModMatrix, Modulation, MOD_ENVELOPES};
+use crate::noise::NoiseParams;
+use crate::pan::PanParams;
+use crate::partials::Partial;
+use crate::pitch::{PitchBend, VibratoParams};
+use crate::queue::Consumer;
+use crate::voices::{
+  default_layers, LayerParams, UnisonParams, VelocityParams, VoiceAllocator, MAX_LAYERS,
+};
+use crate::waves::{NoteMode, PulseParams, WavesControl};
+use crate::wavetable::Wavetable;
+use std::{mem, sync::Arc};
+
+/// Sent by the UI and MIDI threads to the engine that is playing.
+// commands are moved into slots allocated once, which boxing the larger
+// variants would only trade for an allocation freed on the audio thread
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Command {
+  /// Starts `note` with `velocity` between 0 and 1 on every layer playing it.
+  NoteOn {
+    note: usize,
+    velocity: f32,
+  },
+  /// Releases `note` now, or when the pedal lifts if it is down.
+  NoteOff {
+    note: usize,
+  },
+  /// Sustain pedal: while down, released notes keep sounding.
+  Pedal(bool),
+  /// Mode of the first layer.
+  SetMode(NoteMode),
+  SetParam(Param),
+}
+
+/// One setting of [`EngineParams`], already clamped by the sender.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum Param {
+  Layer(usize, LayerParams),
+  NoiseEnvelope(EnvelopeParams),
+  /// Keyboard split, see [`WavesControl::set_split`].
+  Split(Option<usize>),
+  /// Place of one note, see [`WavesControl::set_pan`].
+  NotePan(usize, f32),
+  Pan(PanParams),
+  Velocity(VelocityParams),
+  Pulse(PulseParams),
+  Partials(Arc<[Partial]>),
+  Noise(NoiseParams),
+  Wavetable(Arc<Wavetable>),
+  TablePosition(f32),
+  Bend(PitchBend),
+  Vibrato(VibratoParams),
+  Lfo(usize, LfoParams),
+  Tempo(f32),
+  Filter(FilterParams),
+  Master(f32),
+  Limiter(LimiterParams),
+  ModMatrix(Arc<ModMatrix>),
+  ModEnvelope(usize, EnvelopeParams),
+  Fm(Arc<FmParams>),
+  Unison(UnisonParams),
+}
+
+impl Param {
+  /// Whether these are shared settings someone else still holds.
+  pub fn is_shared(&self) -> bool {
+    match self {
+      Param::Partials(partials) => Arc::strong_count(partials) > 1,
+      Param::Wavetable(table) => Arc::strong_count(table) > 1,
+      Param::ModMatrix(matrix) => Arc::strong_count(matrix) > 1,
+      Param::Fm(fm) => Arc::strong_count(fm) > 1,
+      _ => false,
+    }
+  }
+}
+
+/// Every setting the engines play with. The audio thread keeps its own copy,
+/// changed only by the commands it receives.
+#[derive(Debug, Clone)]
+pub struct EngineParams {
+  /// Mode, envelope, volume and key range of every timbre.
+  pub layers: [LayerParams; MAX_LAYERS],
+  /// Envelope of the noise layer under every note.
+  pub noise_envelope: EnvelopeParams,
+  pub pan: PanParams,
+  pub velocity: VelocityParams,
+  pub unison: UnisonParams,
+  pub bend: PitchBend,
+  pub vibrato: VibratoParams,
+  pub lfos: [LfoParams; LFO_COUNT],
+  /// Beats per minute that tempo-synced LFOs follow.
+  pub tempo: f32,
+  /// Shared by every voice, so it follows the newest note.
+  pub filter: FilterParams,
+  /// Gain of the mix of every voice, before the limiter.
+  pub master: f32,
+  pub limiter: LimiterParams,
+  pub mod_matrix: Arc<ModMatrix>,
+  pub mod_envelopes: [EnvelopeParams; MOD_ENVELOPES],
+  pub mod_inputs: ModInputs,
+  pub pulse: PulseParams,
+  /// Shared settings are swapped whole, so sending them never copies them.
+  pub partials: Arc<[Partial]>,
+  pub noise: NoiseParams,
+  pub fm: Arc<FmParams>,
+  pub wavetable: Arc<Wavetable>,
+  pub table_position: f32,
+  /// Place of every note between -1 (left) and 1 (right).
+  pub pans: Box<[f32]>,
+}
+
+impl EngineParams {
+  pub fn new(note_count: usize, auto_release: bool) -> Self {
+    Self {
+      layers: default_layers().map(|layer| LayerParams {
+        envelope: layer.envelope.with_auto_release(auto_release),
+        ..layer
+      }),
+      noise_envelope: EnvelopeParams::burst(0.01, 0.15).with_auto_release(auto_release),
+      pan: PanParams::default(),
+      velocity: VelocityParams::default(),
+      unison: UnisonParams::default(),
+      bend: PitchBend::default(),
+      vibrato: VibratoParams::default(),
+      lfos: [LfoParams::default(); LFO_COUNT],
+      tempo: 120.0,
+      filter: FilterParams::default(),
+      master: 1.0,
+      limiter: LimiterParams::default(),
+      mod_matrix: Arc::new(ModMatrix::default()),
+      mod_envelopes: [EnvelopeParams::default().with_auto_release(auto_release); MOD_ENVELOPES],
+      mod_inputs: ModInputs::default(),
+      pulse: PulseParams::default(),
+      partials: Arc::new([Partial::new(1.0, 0.0)]),
+      noise: NoiseParams::default(),
+      fm: Arc::new(FmParams::default()),
+      wavetable: Arc::new(Wavetable::basic()),
+      table_position: 0.0,
+      pans: vec![0.0; note_count].into_boxed_slice(),
+    }
+  }
+  /// Applies `param`, handing back the shared settings it replaced, if any.
+  pub fn set(&mut self, param: Param) -> Option<Param> {
+    match param {
+      Param::Layer(index, layer) => self.layers[index] = layer,
+      Param::NoiseEnvelope(envelope) => self.noise_envelope = envelope,
+      Param::Split(note) => {
+        for layer in self.layers.iter_mut() {
+          layer.keys = (0, usize::MAX);
+        }
+        if let Some(note) = note {
+          self.layers[0].keys.1 = note.saturating_sub(1);
+          self.layers[1].keys.0 = note;
+          self.layers[1].enabled = true;
+        }
+      }
+      Param::NotePan(note, pan) => self.pans[note] = pan,
+      Param::Pan(pan) => self.pan = pan,
+      Param::Velocity(velocity) => self.velocity = velocity,
+      Param::Pulse(pulse) => self.pulse = pulse,
+      Param::Partials(partials) => {
+        return Some(Param::Partials(mem::replace(&mut self.partials, partials)));
+      }
+      Param::Noise(noise) => self.noise = noise,
+      Param::Wavetable(table) => {
+        return Some(Param::Wavetable(mem::replace(&mut self.wavetable, table)));
+      }
+      Param::TablePosition(position) => self.table_position = position,
+      Param::Bend(bend) => self.bend = bend,
+      Param::Vibrato(vibrato) => self.vibrato = vibrato,
+      Param::Lfo(index, lfo) => self.lfos[index] = lfo,
+      Param::Tempo(bpm) => self.tempo = bpm,
+      Param::Filter(filter) => self.filter = filter,
+      Param::Master(gain) => self.master = gain,
+      Param::Limiter(limiter) => self.limiter = limiter,
+      Param::ModMatrix(matrix) => {
+        return Some(Param::ModMatrix(mem::replace(&mut self.mod_matrix, matrix)));
+      }
+      Param::ModEnvelope(index, envelope) => self.mod_envelopes[index] = envelope,
+      Param::Fm(fm) => return Some(Param::Fm(mem::replace(&mut self.fm, fm))),
+      Param::Unison(unison) => self.unison = unison,
+    }
+    None
+  }
+  /// Whether the filter does anything, now or once the matrix moves it.
+  pub fn is_filtered(&self) -> bool {
+    !self.filter.is_open() || self.mod_matrix.routes_to(ModDestination::Cutoff)
+  }
+  /// The filter moved by the global destinations of `modulation`.
+  pub fn filter_at(&self, modulation: &Modulation) -> FilterParams {
+    let mut filter = self.filter.shifted(modulation.cutoff);
+    filter.resonance += modulation.resonance;
+    filter
+  }
+  /// Left and right gains of `note` moved by `offset`, as unison copies and
+  /// the modulation matrix move it.
+  pub fn pan_gains(&self, note: usize, offset: f32) -> (f32, f32) {
+    self.pan.gains(note, self.pans[note] + offset)
+  }
+}
+
+/// Everything only the audio thread touches: the voices, its copy of the
+/// settings and the receiving end of the command queue.
+pub struct AudioState {
+  pub voices: VoiceAllocator,
+  pub params: EngineParams,
+  /// The sustain pedal is down.
+  pub sustain: bool,
+  commands: Consumer<Command>,
+  control: Arc<WavesControl>,
+}
+
+impl AudioState {
+  pub fn new(
+    voices: VoiceAllocator,
+    params: EngineParams,
+    commands: Consumer<Command>,
+    control: Arc<WavesControl>,
+  ) -> Self {
+    Self {
+      voices,
+      params,
+      sustain: false,
+      commands,
+      control,
+    }
+  }
+  pub fn control(&self) -> &Arc<WavesControl> {
+    &self.control
+  }
+  /// Carries out every command sent since the last call.
+  pub fn update(&mut self) {
+    while let Some(command) = self.commands.pop() {
+      self.apply(command);
+    }
+    if self.control.has_waiting() {
+      let control = Arc::clone(&self.control);
+      if let Some(mut waiting) = control.waiting() {
+        // nothing more is queued while the lock is held, and whatever is in
+        // the queue was sent before the commands still waiting
+        while let Some(command) = self.commands.pop() {
+          self.apply(command);
+        }
+        while let Some(command) = waiting.take() {
+          self.apply(command);
+        }
+      };
+    }
+    self.control.read_inputs(&mut self.params.mod_inputs);
+  }
+  /// Shows the UI what every voice is playing.
+  pub fn publish(&self) {
+    self.control.publish(&self.voices, &self.params.layers);
+  }
+  fn apply(&mut self, command: Command) {
+    let params = &mut self.params;
+    let envelopes = params.layers.map(|l| l.envelope);
+    match command {
+      Command::NoteOn { note, velocity } => {
+        let layers = params.layers.iter().enumerate();
+        for (layer, _) in layers.filter(|(_, l)| l.plays(note)) {
+          self.voices.start(
+            note,
+            layer,
+            velocity.clamp(0.0, 1.0),
+            params.velocity.apply(velocity),
+            &envelopes,
+            &params.noise_envelope,
+          );
+        }
+      }
+      Command::NoteOff { note } => {
+        if !self.control.auto_release {
+          let noise_envelope = &params.noise_envelope;
+          self
+            .voices
+            .release(note, self.sustain, &envelopes, noise_envelope);
+        }
+      }
+      Command::Pedal(down) => {
+        self.sustain = down;
+        if !down {
+          self.voices.release_held(&envelopes, &params.noise_envelope);
+        }
+      }
+      Command::SetMode(mode) => params.layers[0].mode = mode,
+      Command::SetParam(Param::Layer(index, layer)) => {
+        // sounding notes carry on under the new envelope from where they are
+        let old = &envelopes[index];
+        if *old != layer.envelope {
+          let voices = self.voices.voices_mut().iter_mut();
+          for voice in voices.filter(|v| v.layer == index) {
+            voice.state = voice.state.rebase(old, &layer.envelope);
+          }
+        }
+        params.set(Param::Layer(index, layer));
+      }
+      Command::SetParam(Param::NoiseEnvelope(envelope)) => {
+        let old = &params.noise_envelope;
+        if *old != envelope {
+          for voice in self.voices.voices_mut() {
+            voice.noise = voice.noise.rebase(old, &envelope);
+          }
+        }
+        params.set(Param::NoiseEnvelope(envelope));
+      }
+      Command::SetParam(param) => {
+        // the control keeps whatever this replaces until the engine lets go
+        // of it, so dropping it here never frees memory
+        params.set(param);
+      }
+    }
+  }
+}
+
+#[test]
+fn test_split() {
+  let mut params = EngineParams::new(88, false);
+  params.set(Param::Split(Some(48)));
+  assert!(params.layers[0].plays(47) && !params.layers[0].plays(48));
+  assert!(params.layers[1].plays(48) && !params.layers[1].plays(47));
+  params.set(Param::Split(None));
+  assert!(params.layers[0].plays(48) && params.layers[1].plays(47));
+}
+
+#[test]
+fn test_layer_rebase() {
+  let mut audio = crate::waves::Waves::builder().build().unwrap().into_audio();
+  let control = Arc::clone(audio.control());
+  let old = audio.params.layers[0].envelope;
+  let new = EnvelopeParams::adsr(1.0, 0.01, 0.1, 0.25, 0.2).unwrap();
+  control.hit(40, 1.0);
+  audio.update();
+  let state = &mut audio.voices.voices_mut()[0].state;
+  for _ in 0..100 {
+    state.next(&old, 0.01, false);
+  }
+  let sounding = *state;
+  // the control side only queues the change; sounding notes are rebased
+  // by the audio thread once it takes the command
+  control.set_envelope(0, new);
+  assert_eq!(audio.voices.voices()[0].state, sounding);
+  audio.update();
+  assert_eq!(audio.params.layers[0].envelope, new);
+  assert_eq!(audio.voices.voices()[0].state, sounding.rebase(&old, &new));
+}
+
+#[test]
+fn test_full_queue() {
+  let mut audio = crate::waves::Waves::builder().build().unwrap().into_audio();
+  let control = Arc::clone(audio.control());
+  control.set_pedal(true);
+  // more changes than the queue holds, so the pedal lifting has to wait
+  for i in 0..2000 {
+    control.set_tempo(60.0 + i as f32 * 0.01);
+  }
+  control.set_pedal(false);
+  control.set_cc(7, 0.5);
+  control.set_cc(7, 0.25);
+  audio.update();
+  assert!(!audio.sustain);
+  assert_eq!(audio.params.tempo, control.tempo());
+  assert_eq!(audio.params.mod_inputs.cc[7], 0.25);
+}
+
+#[test]
+fn test_retired_settings() {
+  let mut audio = crate::waves::Waves::builder().build().unwrap().into_audio();
+  let control = Arc::clone(audio.control());
+  let old = Arc::downgrade(&audio.params.partials);
+  control.set_partials(vec![Partial::new(1.0, 0.0), Partial::new(0.5, 0.0)]);
+  audio.update();
+  // the engine let go of the old partials, but the control still has them
+  assert_eq!(audio.params.partials.len(), 2);
+  assert_eq!(old.strong_count(), 1);
+  control.set_tempo(100.0);
+  assert_eq!(old.strong_count(), 0);
+}
+
+#[test]
+fn test_noise_auto_release() {
+  let waves = crate::waves::Waves::builder().auto_release(true).build();
+  let mut audio = waves.unwrap().into_audio();
+  let control = Arc::clone(audio.control());
+  control.set_noise_envelope(EnvelopeParams::adsr(1.0, 0.01, 0.01, 0.5, 0.1).unwrap());
+  control.hit(40, 1.0);
+  audio.update();
+  // no note-off ever comes, so the noise has to let go of its sustain alone
+  let envelope = &audio.params.noise_envelope;
+  let noise = &mut audio.voices.voices_mut()[0].noise;
+  for _ in 0..1000 {
+    noise.next(envelope, 0.01, false);
+  }
+  assert_eq!(*noise, crate::envelope::NoteState::Silent);
+}
+
+#[test]
+fn test_bad_index() {
+  let control = crate::waves::Waves::builder().build().unwrap().control();
+  let layers = control.layers();
+  control.set_layer(MAX_LAYERS, layers[0]);
+  control.set_envelope(MAX_LAYERS, EnvelopeParams::default());
+  control.set_lfo(LFO_COUNT, LfoParams::default());
+  control.set_mod_envelope(MOD_ENVELOPES, EnvelopeParams::default());
+  // the calls are ignored and the control still answers
+  assert_eq!(control.layers(), layers);
+  assert_eq!(control.lfos().len(), LFO_COUNT);
+}
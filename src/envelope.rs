@@ -0,0 +1,636 @@
+use crate::lerp::{curve_as, inv_lerp, lerp, Curve};
+
+/// Longest a single segment of an envelope may last.
+pub const MAX_STAGE_SECS: f32 = 60.0;
+/// Most breakpoints an envelope can have.
+pub const MAX_BREAKPOINTS: usize = 8;
+
+/// A level an envelope moves to, and how it gets there from the point before.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+  /// Amplitude of a full-velocity note.
+  pub level: f32,
+  /// Seconds taken to get here.
+  pub time: f32,
+  pub curve: Curve,
+}
+
+impl Breakpoint {
+  pub const fn new(level: f32, time: f32) -> Self {
+    Self {
+      level,
+      time,
+      curve: Curve::Exponential,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeError {
+  /// Between 1 and [`MAX_BREAKPOINTS`] points are needed.
+  PointCount { count: usize },
+  /// Levels must be within 0..=1.
+  InvalidLevel { point: usize, level: f32 },
+  /// A point must be reached in zero up to [`MAX_STAGE_SECS`] seconds.
+  InvalidDuration { point: usize, secs: f32 },
+  /// No point rises above zero.
+  Silent,
+  /// The last point is not at zero, so notes would never end.
+  Unfinished,
+  /// The sustain point has nothing after it to release to.
+  InvalidSustain { point: usize },
+  /// A loop must run forwards, take some time and end by the sustain point.
+  InvalidLoop { start: usize, end: usize },
+}
+
+impl std::fmt::Display for EnvelopeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    // points are numbered from 1, as in presets
+    match *self {
+      EnvelopeError::PointCount { count } => write!(
+        f,
+        "an envelope needs 1 to {MAX_BREAKPOINTS} points, got {count}"
+      ),
+      EnvelopeError::InvalidLevel { point, level } => write!(
+        f,
+        "point {} level must be within 0..=1, got {level}",
+        point + 1
+      ),
+      EnvelopeError::InvalidDuration { point, secs } => write!(
+        f,
+        "point {} must take from 0 to {MAX_STAGE_SECS} s, got {secs}",
+        point + 1
+      ),
+      EnvelopeError::Silent => write!(f, "an envelope needs a point above zero"),
+      EnvelopeError::Unfinished => write!(f, "the last point must be at zero"),
+      EnvelopeError::InvalidSustain { point } => write!(
+        f,
+        "sustain point {} needs a point after it to release to",
+        point + 1
+      ),
+      EnvelopeError::InvalidLoop { start, end } => write!(
+        f,
+        "loop {}-{} must run forwards, take time and end by the sustain point",
+        start + 1,
+        end + 1
+      ),
+    }
+  }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// Levels a note moves through, starting from silence. While the key is
+/// down the envelope loops or stops at the sustain point; note-off carries
+/// on from the point after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnvelopeParams {
+  points: [Breakpoint; MAX_BREAKPOINTS],
+  len: usize,
+  sustain: Option<usize>,
+  /// First and last point played over and over while the key is down.
+  looped: Option<(usize, usize)>,
+  /// Seconds the sustain point is held when releasing on a timer.
+  hold: f32,
+  /// Release on a timer after the sustain instead of waiting for note-off.
+  auto_release: bool,
+}
+
+impl Default for EnvelopeParams {
+  fn default() -> Self {
+    Self::adsr(0.4, 0.2, 0.04, 0.3, 0.15).unwrap()
+  }
+}
+
+impl EnvelopeParams {
+  /// Moves through `points` in order, holding at `sustain` and repeating
+  /// `looped` (both indices into `points`) until note-off.
+  pub fn new(
+    points: &[Breakpoint],
+    sustain: Option<usize>,
+    looped: Option<(usize, usize)>,
+  ) -> Result<Self, EnvelopeError> {
+    let len = points.len();
+    if !(1..=MAX_BREAKPOINTS).contains(&len) {
+      return Err(EnvelopeError::PointCount { count: len });
+    }
+    for (point, p) in points.iter().enumerate() {
+      if !(0.0..=1.0).contains(&p.level) {
+        return Err(EnvelopeError::InvalidLevel {
+          point,
+          level: p.level,
+        });
+      }
+      if !(0.0..=MAX_STAGE_SECS).contains(&p.time) {
+        return Err(EnvelopeError::InvalidDuration {
+          point,
+          secs: p.time,
+        });
+      }
+    }
+    if points.iter().all(|p| p.level == 0.0) {
+      return Err(EnvelopeError::Silent);
+    }
+    if points[len - 1].level != 0.0 {
+      return Err(EnvelopeError::Unfinished);
+    }
+    if let Some(point) = sustain.filter(|&s| s + 1 >= len) {
+      return Err(EnvelopeError::InvalidSustain { point });
+    }
+    if let Some((start, end)) = looped {
+      if start > end || end >= len {
+        return Err(EnvelopeError::InvalidLoop { start, end });
+      }
+      let time: f32 = points[start..=end].iter().map(|p| p.time).sum();
+      if time <= 0.0 || !matches!(sustain, Some(s) if end <= s) {
+        return Err(EnvelopeError::InvalidLoop { start, end });
+      }
+    }
+    let mut all = [Breakpoint::new(0.0, 0.0); MAX_BREAKPOINTS];
+    all[..len].copy_from_slice(points);
+    Ok(Self {
+      points: all,
+      len,
+      sustain,
+      looped,
+      hold: 0.2,
+      auto_release: false,
+    })
+  }
+  /// Rises to `peak` over `attack` seconds, falls to `sustain` over `decay`
+  /// and, once released, to silence over `release`.
+  pub fn adsr(
+    peak: f32,
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+  ) -> Result<Self, EnvelopeError> {
+    let points = [
+      Breakpoint::new(peak, attack),
+      Breakpoint::new(sustain, decay),
+      Breakpoint::new(0.0, release),
+    ];
+    Self::new(&points, Some(1), None)
+  }
+  /// Envelope that rises and dies away again while the key is still held,
+  /// for the breath or chiff at the start of a note.
+  pub fn burst(attack: f32, decay: f32) -> Self {
+    Self::adsr(0.4, attack, decay, 0.0, 0.05).unwrap()
+  }
+  pub fn with_auto_release(mut self, auto_release: bool) -> Self {
+    self.auto_release = auto_release;
+    self
+  }
+  /// Curves of the first points, in order.
+  pub fn with_curves(mut self, curves: &[Curve]) -> Self {
+    for (p, curve) in self.points[..self.len].iter_mut().zip(curves) {
+      p.curve = *curve;
+    }
+    self
+  }
+  /// The same envelope with point `index` replaced.
+  pub fn with_point(&self, index: usize, point: Breakpoint) -> Result<Self, EnvelopeError> {
+    let mut points = self.points().to_vec();
+    points[index] = point;
+    Ok(Self {
+      hold: self.hold,
+      auto_release: self.auto_release,
+      ..Self::new(&points, self.sustain, self.looped)?
+    })
+  }
+  pub fn points(&self) -> &[Breakpoint] {
+    &self.points[..self.len]
+  }
+  pub fn sustain(&self) -> Option<usize> {
+    self.sustain
+  }
+  pub fn looped(&self) -> Option<(usize, usize)> {
+    self.looped
+  }
+  /// Highest level of any point.
+  pub fn peak(&self) -> f32 {
+    self.points().iter().fold(0.0, |peak, p| peak.max(p.level))
+  }
+  /// `level/secs/curve` for each point, separated by commas, then
+  /// `sustain=<n>` and `loop=<first>-<last>` if set, counting from 1.
+  pub fn name(&self) -> String {
+    let mut fields: Vec<String> = self
+      .points()
+      .iter()
+      .map(|p| format!("{}/{}/{}", p.level, p.time, p.curve.name()))
+      .collect();
+    if let Some(s) = self.sustain {
+      fields.push(format!("sustain={}", s + 1));
+    }
+    if let Some((start, end)) = self.looped {
+      fields.push(format!("loop={}-{}", start + 1, end + 1));
+    }
+    fields.join(",")
+  }
+  /// Reads what [`EnvelopeParams::name`] writes; a point's curve may be left
+  /// out, making it exponential.
+  pub fn from_name(name: &str) -> Option<Self> {
+    let (mut points, mut sustain, mut looped) = (vec![], None, None);
+    let index = |n: &str| n.parse::<usize>().ok()?.checked_sub(1);
+    for field in name.split(',') {
+      if let Some(n) = field.strip_prefix("sustain=") {
+        sustain = Some(index(n)?);
+      } else if let Some(range) = field.strip_prefix("loop=") {
+        let (start, end) = range.split_once('-')?;
+        looped = Some((index(start)?, index(end)?));
+      } else {
+        let mut parts = field.splitn(3, '/');
+        let mut point = Breakpoint::new(parts.next()?.parse().ok()?, parts.next()?.parse().ok()?);
+        if let Some(curve) = parts.next() {
+          point.curve = Curve::from_name(curve)?;
+        }
+        points.push(point);
+      }
+    }
+    Self::new(&points, sustain, looped).ok()
+  }
+  /// Seconds to rest on the sustain point.
+  fn hold_secs(&self, pedal: bool) -> f32 {
+    if !self.auto_release {
+      f32::INFINITY
+    } else if pedal {
+      self.hold
+    } else {
+      self.hold / 4.0
+    }
+  }
+}
+
+/// When envelopes are stepped, counted in samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControlClock {
+  /// Samples between steps, at least one.
+  pub period: f32,
+  /// Samples since the last step.
+  pub phase: f32,
+  /// Seconds per sample.
+  pub sample_dt: f32,
+}
+
+impl ControlClock {
+  pub fn new(sample_rate: u32, control_rate: f32) -> Self {
+    Self {
+      period: sample_rate as f32 / control_rate,
+      phase: 0.0,
+      sample_dt: 1.0 / sample_rate as f32,
+    }
+  }
+  pub fn advance(&mut self, samples: usize) {
+    self.phase = (self.phase + samples as f32) % self.period;
+  }
+}
+
+/// Where a note is in its envelope.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteState {
+  Silent,
+  /// Heading from level `from` to breakpoint `point`, `t` seconds in.
+  Moving {
+    point: usize,
+    t: f32,
+    from: f32,
+    released: bool,
+  },
+  /// Resting on the sustain point for `left` more seconds.
+  Holding {
+    left: f32,
+  },
+}
+
+impl NoteState {
+  /// Level now, then moves on by `dt` seconds.
+  #[inline(always)]
+  pub fn next(&mut self, envelope: &EnvelopeParams, dt: f32, pedal: bool) -> f32 {
+    use NoteState::*;
+    let level = self.peek(envelope);
+    let next = match *self {
+      Silent => Silent,
+      Holding { left } if left > 0.0 => Holding { left: left - dt },
+      Holding { .. } => self.release(envelope),
+      Moving {
+        point,
+        t,
+        from,
+        released,
+      } => {
+        let time = envelope.points[point].time;
+        if t + dt < time {
+          Moving {
+            point,
+            t: t + dt,
+            from,
+            released,
+          }
+        } else {
+          Self::arrive(envelope, point, t + dt - time, released, pedal)
+        }
+      }
+    };
+    *self = next;
+    level
+  }
+  /// Fills `levels` with one level per sample from now on, stepping the
+  /// envelope on `clock` and going in a straight line between steps. Only
+  /// the steps up to `commit` samples from now are kept; the rest look ahead.
+  pub fn render(
+    &mut self,
+    envelope: &EnvelopeParams,
+    pedal: bool,
+    clock: &ControlClock,
+    commit: usize,
+    levels: &mut [f32],
+  ) {
+    if *self == NoteState::Silent {
+      levels.fill(0.0);
+      return;
+    }
+    let dt = clock.period * clock.sample_dt;
+    let mut ahead = *self;
+    let mut from = ahead.peek(envelope);
+    ahead.next(envelope, dt, pedal);
+    let mut to = ahead.peek(envelope);
+    // samples from now to the step `ahead` has reached
+    let mut step = clock.period - clock.phase;
+    let commit = commit as f32;
+    for (i, level) in levels.iter_mut().enumerate() {
+      let i = i as f32;
+      while i > step {
+        if step <= commit {
+          *self = ahead;
+        }
+        from = to;
+        ahead.next(envelope, dt, pedal);
+        to = ahead.peek(envelope);
+        step += clock.period;
+      }
+      *level = lerp(1.0 - (step - i) / clock.period, from, to);
+    }
+    while step <= commit {
+      *self = ahead;
+      ahead.next(envelope, dt, pedal);
+      step += clock.period;
+    }
+  }
+  /// What comes after reaching `point`, `over` seconds ago.
+  fn arrive(
+    envelope: &EnvelopeParams,
+    point: usize,
+    over: f32,
+    released: bool,
+    pedal: bool,
+  ) -> Self {
+    let from = envelope.points[point].level;
+    match (envelope.looped, envelope.sustain) {
+      (Some((start, end)), _) if point == end && !released => NoteState::Moving {
+        point: start,
+        t: over,
+        from,
+        released,
+      },
+      (_, Some(s)) if point == s && !released => NoteState::Holding {
+        left: envelope.hold_secs(pedal),
+      },
+      _ if point + 1 < envelope.len => NoteState::Moving {
+        point: point + 1,
+        t: over,
+        from,
+        released,
+      },
+      _ => NoteState::Silent,
+    }
+  }
+  /// Moves on past the sustain point, starting from the level the note is at.
+  pub fn release(&self, envelope: &EnvelopeParams) -> Self {
+    use NoteState::*;
+    let from = self.peek(envelope);
+    match (*self, envelope.sustain) {
+      (Silent, _) | (Moving { released: true, .. }, _) => *self,
+      (_, Some(s)) => Moving {
+        point: s + 1,
+        t: 0.0,
+        from,
+        released: true,
+      },
+      // without a sustain point the envelope plays out as it would have
+      (Moving { point, t, from, .. }, None) => Moving {
+        point,
+        t,
+        from,
+        released: true,
+      },
+      (Holding { .. }, None) => Moving {
+        point: envelope.len - 1,
+        t: 0.0,
+        from,
+        released: true,
+      },
+    }
+  }
+  pub fn is_releasing(&self) -> bool {
+    matches!(
+      self,
+      NoteState::Silent | NoteState::Moving { released: true, .. }
+    )
+  }
+  /// Restarts the envelope from the level the note is currently at.
+  pub fn retrigger(&self, envelope: &EnvelopeParams) -> Self {
+    Self::attack_from(self.peek(envelope), envelope)
+  }
+  /// Start of the envelope, from `level` rather than from silence. Below
+  /// the first point, the note joins its way up where it passes `level`.
+  pub fn attack_from(level: f32, envelope: &EnvelopeParams) -> Self {
+    let first = envelope.points[0];
+    let (t, from) = if level < first.level {
+      let share = inv_lerp(level, 0.0, first.level);
+      (first.curve.inverse(share) * first.time, 0.0)
+    } else {
+      (0.0, level)
+    };
+    NoteState::Moving {
+      point: 0,
+      t,
+      from,
+      released: false,
+    }
+  }
+  /// The same note under the `new` envelope, carrying on from the level it
+  /// is at under `old`, so that changing the envelope of a sounding note
+  /// does not click. A segment that changed starts over from that level.
+  pub fn rebase(&self, old: &EnvelopeParams, new: &EnvelopeParams) -> Self {
+    use NoteState::*;
+    let from = self.peek(old);
+    match *self {
+      Silent => Silent,
+      Moving {
+        point: current,
+        released,
+        ..
+      } => {
+        let mut point = current.min(new.len - 1);
+        match new.sustain {
+          Some(s) if released => point = point.max(s + 1),
+          Some(s) => point = point.min(s),
+          None => (),
+        }
+        if point == current && old.points.get(point) == new.points.get(point) {
+          return *self;
+        }
+        Moving {
+          point,
+          t: 0.0,
+          from,
+          released,
+        }
+      }
+      Holding { left } => match new.sustain {
+        Some(s) if new.points[s].level == from => Holding { left },
+        // glide to the new sustain level
+        Some(s) => Moving {
+          point: s,
+          t: 0.0,
+          from,
+          released: false,
+        },
+        None => Holding { left }.release(new),
+      },
+    }
+  }
+  #[inline(always)]
+  pub fn peek(&self, envelope: &EnvelopeParams) -> f32 {
+    match *self {
+      NoteState::Silent => 0.0,
+      NoteState::Holding { .. } => envelope.sustain.map_or(0.0, |s| envelope.points[s].level),
+      NoteState::Moving { point, t, from, .. } => {
+        let p = &envelope.points[point];
+        if t < p.time {
+          curve_as(p.curve, t, 0.0, p.time, from, p.level)
+        } else {
+          p.level
+        }
+      }
+    }
+  }
+}
+
+#[test]
+fn test_envelope() {
+  assert!(EnvelopeParams::adsr(1.0, 0.01, 0.2, 0.5, 0.3).is_ok());
+  assert_eq!(
+    EnvelopeParams::adsr(0.0, 0.01, 0.2, 0.0, 0.3),
+    Err(EnvelopeError::Silent)
+  );
+  assert!(matches!(
+    EnvelopeParams::adsr(1.0, 0.01, -0.2, 0.5, 0.3),
+    Err(EnvelopeError::InvalidDuration { point: 1, .. })
+  ));
+  assert!(EnvelopeParams::adsr(1.0, 0.01, 0.2, 1.5, 0.3).is_err());
+  let points = [Breakpoint::new(1.0, 0.1), Breakpoint::new(0.0, 0.1)];
+  assert!(EnvelopeParams::new(&points, Some(1), None).is_err());
+  assert!(EnvelopeParams::new(&points, Some(0), Some((0, 1))).is_err());
+  // pulses between 1 and 0.2 every 0.2 s while held, then fades out
+  let pulses = [
+    Breakpoint::new(1.0, 0.1),
+    Breakpoint::new(0.2, 0.1),
+    Breakpoint::new(0.0, 0.5),
+  ];
+  let pulses = EnvelopeParams::new(&pulses, Some(1), Some((0, 1))).unwrap();
+  assert_eq!(EnvelopeParams::from_name(&pulses.name()), Some(pulses));
+  let dt = 0.001;
+  let mut state = NoteState::attack_from(0.0, &pulses);
+  let levels: Vec<f32> = (0..1000).map(|_| state.next(&pulses, dt, false)).collect();
+  for cycle in 0..4 {
+    let peak = levels[cycle * 200 + 100];
+    let dip = levels[cycle * 200 + 200];
+    assert!((peak - 1.0).abs() < 0.05, "{cycle}: {peak}");
+    assert!((dip - 0.2).abs() < 0.05, "{cycle}: {dip}");
+  }
+  state = state.release(&pulses);
+  for _ in 0..600 {
+    state.next(&pulses, dt, false);
+  }
+  assert_eq!(state, NoteState::Silent);
+}
+
+#[test]
+fn test_loop_range() {
+  let points = [
+    Breakpoint::new(1.0, 0.1),
+    Breakpoint::new(0.5, 0.1),
+    Breakpoint::new(0.0, 0.1),
+  ];
+  for looped in [(3, 3), (5, 5), (1, 0), (0, 4)] {
+    assert_eq!(
+      EnvelopeParams::new(&points, Some(1), Some(looped)),
+      Err(EnvelopeError::InvalidLoop {
+        start: looped.0,
+        end: looped.1
+      })
+    );
+  }
+  assert_eq!(
+    EnvelopeParams::from_name("1/0.1,0/0.1,sustain=1,loop=5-5"),
+    None
+  );
+}
+
+#[test]
+fn test_rebase() {
+  let old = EnvelopeParams::adsr(1.0, 0.01, 0.2, 0.5, 0.3).unwrap();
+  let new = EnvelopeParams::adsr(0.8, 0.5, 1.0, 0.25, 2.0).unwrap();
+  // each segment carries on from the level it had reached
+  for state in [
+    NoteState::attack_from(0.3, &old),
+    NoteState::Moving {
+      point: 1,
+      t: 0.1,
+      from: 1.0,
+      released: false,
+    },
+    NoteState::Holding {
+      left: f32::INFINITY,
+    },
+    NoteState::Holding { left: 0.0 }.release(&old),
+  ] {
+    let level = state.peek(&old);
+    let rebased = state.rebase(&old, &new);
+    assert!(
+      (rebased.peek(&new) - level).abs() < 1e-4,
+      "{state:?} {rebased:?}"
+    );
+  }
+  let louder = EnvelopeParams::adsr(1.0, 0.01, 0.2, 0.75, 0.3).unwrap();
+  let swell = NoteState::Holding { left: 1.0 }.rebase(&old, &louder);
+  assert!(
+    matches!(swell, NoteState::Moving { point: 1, .. }),
+    "{swell:?}"
+  );
+  assert_eq!(NoteState::Silent.rebase(&old, &new), NoteState::Silent);
+}
+
+#[test]
+fn test_render() {
+  let env = EnvelopeParams::adsr(1.0, 0.002, 0.05, 0.5, 0.1).unwrap();
+  let mut clock = ControlClock::new(44100, 44100.0);
+  let mut levels = [0.0; 512];
+  let mut state = NoteState::attack_from(0.0, &env);
+  let mut stepped = state;
+  state.render(&env, false, &clock, 128, &mut levels);
+  // a 2ms attack peaks within the first hundred samples
+  assert!(levels[..100].iter().any(|level| *level > 0.99));
+  for _ in 0..128 {
+    stepped.next(&env, clock.sample_dt, false);
+  }
+  assert!((state.peek(&env) - stepped.peek(&env)).abs() < 1e-4);
+  // a coarser clock goes in straight lines between steps
+  clock = ControlClock::new(44100, 441.0);
+  let mut state = NoteState::Holding { left: 0.0 }.release(&env);
+  state.render(&env, false, &clock, 0, &mut levels);
+  assert!((levels[50] - (levels[0] + levels[100]) / 2.0).abs() < 1e-4);
+  assert!(levels.windows(2).all(|w| w[1] <= w[0]));
+}
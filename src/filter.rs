@@ -0,0 +1,84 @@
+use std::f32::consts::{FRAC_1_SQRT_2, PI};
+
+/// Cutoff at and above which the filter is left out altogether.
+pub const FILTER_OPEN_HZ: f32 = 20000.0;
+
+/// Resonant lowpass over the mix of every voice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FilterParams {
+  /// In Hz; [`FILTER_OPEN_HZ`] and up lets everything through.
+  pub cutoff: f32,
+  /// Q of the two poles; `FRAC_1_SQRT_2` is flat up to the cutoff.
+  pub resonance: f32,
+}
+
+impl Default for FilterParams {
+  fn default() -> Self {
+    Self {
+      cutoff: FILTER_OPEN_HZ,
+      resonance: FRAC_1_SQRT_2,
+    }
+  }
+}
+
+impl FilterParams {
+  pub fn is_open(&self) -> bool {
+    self.cutoff >= FILTER_OPEN_HZ
+  }
+  /// The same filter with its cutoff moved by `octaves`.
+  pub fn shifted(&self, octaves: f32) -> Self {
+    Self {
+      cutoff: self.cutoff * 2f32.powf(octaves),
+      ..*self
+    }
+  }
+  fn damping(&self) -> f32 {
+    1.0 / self.resonance.max(0.1)
+  }
+  /// Gain at `freq` Hz of the analog two-pole lowpass that [`Svf`] models,
+  /// for the spectral engine to apply bin by bin.
+  pub fn gain(&self, freq: f32) -> f32 {
+    let x = freq / self.cutoff.max(1.0);
+    1.0 / ((1.0 - x * x).powi(2) + (x * self.damping()).powi(2)).sqrt()
+  }
+}
+
+/// Coefficients of an [`Svf`], shared by both channels.
+#[derive(Debug, Clone, Copy)]
+pub struct SvfCoefs {
+  a1: f32,
+  a2: f32,
+  a3: f32,
+}
+
+impl SvfCoefs {
+  pub fn new(params: &FilterParams, sample_rate: f32) -> Self {
+    // prewarped, so the cutoff lands where the analog one does
+    let g = (PI * (params.cutoff / sample_rate).min(0.49)).tan();
+    let a1 = 1.0 / (1.0 + g * (g + params.damping()));
+    Self {
+      a1,
+      a2: g * a1,
+      a3: g * g * a1,
+    }
+  }
+}
+
+/// Trapezoidal state-variable filter, lowpass output: it stays stable while
+/// its cutoff is swept sample by sample.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Svf {
+  ic1: f32,
+  ic2: f32,
+}
+
+impl Svf {
+  pub fn process(&mut self, x: f32, coefs: &SvfCoefs) -> f32 {
+    let v3 = x - self.ic2;
+    let v1 = coefs.a1 * self.ic1 + coefs.a2 * v3;
+    let v2 = self.ic2 + coefs.a2 * self.ic1 + coefs.a3 * v3;
+    self.ic1 = 2.0 * v1 - self.ic1;
+    self.ic2 = 2.0 * v2 - self.ic2;
+    v2
+  }
+}
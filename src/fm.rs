@@ -0,0 +1,262 @@
+use crate::envelope::{EnvelopeParams, NoteState};
+use crate::voices::Voice;
+use std::f32::consts::TAU;
+
+pub const MAX_OPERATORS: usize = 6;
+pub const MIN_OPERATORS: usize = 2;
+
+/// How operators feed each other. Operators are numbered from the carrier up,
+/// and an operator is only ever modulated by higher-numbered ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FmAlgorithm {
+  /// Each operator modulates the one below it; operator 0 is heard.
+  Stack,
+  /// Operators form modulator-carrier pairs, every even operator is heard.
+  Pairs,
+  /// Every other operator modulates operator 0, which is heard.
+  Branch,
+  /// Two stacks side by side, the bottom of each is heard.
+  TwoStacks,
+  /// No modulation at all: an organ of sines.
+  Parallel,
+}
+
+impl FmAlgorithm {
+  pub const ALL: [FmAlgorithm; 5] = [
+    FmAlgorithm::Stack,
+    FmAlgorithm::Pairs,
+    FmAlgorithm::Branch,
+    FmAlgorithm::TwoStacks,
+    FmAlgorithm::Parallel,
+  ];
+  pub fn name(self) -> &'static str {
+    match self {
+      FmAlgorithm::Stack => "stack",
+      FmAlgorithm::Pairs => "pairs",
+      FmAlgorithm::Branch => "branch",
+      FmAlgorithm::TwoStacks => "two-stacks",
+      FmAlgorithm::Parallel => "parallel",
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|a| a.name() == name)
+  }
+  /// Whether operator `from` modulates operator `to`, out of `count`.
+  pub fn modulates(self, from: usize, to: usize, count: usize) -> bool {
+    if from <= to || from >= count {
+      return false;
+    }
+    match self {
+      FmAlgorithm::Stack => from == to + 1,
+      FmAlgorithm::Pairs => to.is_multiple_of(2) && from == to + 1,
+      FmAlgorithm::Branch => to == 0,
+      FmAlgorithm::TwoStacks => from == to + 1 && from != count.div_ceil(2),
+      FmAlgorithm::Parallel => false,
+    }
+  }
+  /// Whether operator `op` is heard rather than only modulating others.
+  pub fn is_carrier(self, op: usize, count: usize) -> bool {
+    match self {
+      FmAlgorithm::Stack | FmAlgorithm::Branch => op == 0,
+      FmAlgorithm::Pairs => op.is_multiple_of(2),
+      FmAlgorithm::TwoStacks => op == 0 || op == count.div_ceil(2),
+      FmAlgorithm::Parallel => true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Operator {
+  /// Frequency as a multiple of the note's.
+  pub ratio: f32,
+  /// Offset from `ratio` in cents.
+  pub detune: f32,
+  /// Output amplitude of a carrier, or peak modulation index in radians of a
+  /// modulator.
+  pub level: f32,
+  /// Own envelope on top of the note's, peaking at one; `None` follows the
+  /// note's envelope alone.
+  pub envelope: Option<EnvelopeParams>,
+}
+
+impl Operator {
+  pub fn new(ratio: f32, level: f32) -> Self {
+    Self {
+      ratio,
+      detune: 0.0,
+      level,
+      envelope: None,
+    }
+  }
+  /// Frequency multiple with the detune applied.
+  pub fn multiple(&self) -> f32 {
+    self.ratio * 2f32.powf(self.detune / 1200.0)
+  }
+}
+
+/// Voice type used by [`crate::waves::NoteMode::Fm`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FmParams {
+  /// Only the first `count` are used.
+  pub operators: [Operator; MAX_OPERATORS],
+  pub count: usize,
+  pub algorithm: FmAlgorithm,
+  /// Self-modulation of the highest operator, in radians.
+  pub feedback: f32,
+}
+
+impl Default for FmParams {
+  fn default() -> Self {
+    let mut operators = [Operator::new(1.0, 1.0); MAX_OPERATORS];
+    // a bright attack that mellows into a sine
+    operators[1] = Operator {
+      envelope: Some(EnvelopeParams::burst(0.005, 0.8)),
+      ..Operator::new(1.0, 2.0)
+    };
+    Self {
+      operators,
+      count: MIN_OPERATORS,
+      algorithm: FmAlgorithm::Stack,
+      feedback: 0.0,
+    }
+  }
+}
+
+impl FmParams {
+  pub fn operators(&self) -> &[Operator] {
+    &self.operators[..self.count.clamp(MIN_OPERATORS, MAX_OPERATORS)]
+  }
+}
+
+/// Running state of one voice's operators.
+#[derive(Debug, Clone, Copy)]
+pub struct FmVoice {
+  phases: [f32; MAX_OPERATORS],
+  envelopes: [NoteState; MAX_OPERATORS],
+  /// Last two outputs of the feedback operator, averaged to keep it stable.
+  history: [f32; 2],
+  /// `Voice::started` of the note the envelopes belong to.
+  started: u64,
+  released: bool,
+}
+
+impl Default for FmVoice {
+  fn default() -> Self {
+    Self {
+      phases: [0.0; MAX_OPERATORS],
+      envelopes: [NoteState::Silent; MAX_OPERATORS],
+      history: [0.0; 2],
+      started: 0,
+      released: true,
+    }
+  }
+}
+
+impl FmVoice {
+  /// Next sample of `voice` at `freq` cycles per sample, with carriers summing
+  /// to unit amplitude and the modulation scaled by `brightness`. The note's
+  /// own envelope is left to the caller.
+  pub fn next(
+    &mut self,
+    voice: &Voice,
+    params: &FmParams,
+    freq: f32,
+    brightness: f32,
+    dt: f32,
+    sustain: bool,
+  ) -> f32 {
+    self.follow(voice, params);
+    let operators = params.operators();
+    let count = operators.len();
+    let mut outputs = [0.0; MAX_OPERATORS];
+    let (mut sum, mut carriers) = (0.0, 0);
+    for (i, op) in operators.iter().enumerate().rev() {
+      let env = match &op.envelope {
+        Some(envelope) => self.envelopes[i].next(envelope, dt, sustain) / envelope.peak(),
+        None => 1.0,
+      };
+      let mut index = (i + 1..count)
+        .filter(|&j| params.algorithm.modulates(j, i, count))
+        .map(|j| outputs[j])
+        .sum::<f32>();
+      if i == count - 1 {
+        index += params.feedback * (self.history[0] + self.history[1]) / 2.0;
+      }
+      // soft notes are played with less modulation, so they come out darker
+      let y = op.level * env * (TAU * self.phases[i] + brightness * index).sin();
+      if i == count - 1 {
+        self.history = [y, self.history[0]];
+      }
+      outputs[i] = y;
+      if params.algorithm.is_carrier(i, count) {
+        sum += y;
+        carriers += 1;
+      }
+      self.phases[i] = (self.phases[i] + freq * op.multiple()).fract();
+    }
+    sum / carriers.max(1) as f32
+  }
+  /// Starts the operator envelopes with a new note and releases them with it.
+  fn follow(&mut self, voice: &Voice, params: &FmParams) {
+    if voice.started != self.started {
+      self.started = voice.started;
+      self.released = false;
+      for (env, op) in self.envelopes.iter_mut().zip(&params.operators) {
+        if let Some(envelope) = &op.envelope {
+          *env = env.retrigger(envelope);
+        }
+      }
+    }
+    if !self.released && voice.state.is_releasing() {
+      self.released = true;
+      for (env, op) in self.envelopes.iter_mut().zip(&params.operators) {
+        if let Some(envelope) = &op.envelope {
+          *env = env.release(envelope);
+        }
+      }
+    }
+  }
+}
+
+#[test]
+fn test_algorithms() {
+  let count = 4;
+  for algorithm in FmAlgorithm::ALL {
+    // the carrier at the bottom is always heard, the top is never modulated
+    assert!(algorithm.is_carrier(0, count));
+    assert!((0..count).all(|j| !algorithm.modulates(j, count - 1, count)));
+  }
+  assert!(FmAlgorithm::Stack.modulates(3, 2, count));
+  assert!(!FmAlgorithm::TwoStacks.modulates(2, 1, count));
+  assert!(FmAlgorithm::TwoStacks.is_carrier(2, count));
+  assert!(FmAlgorithm::Branch.modulates(3, 0, count));
+}
+
+#[test]
+fn test_brightness() {
+  let voice = Voice {
+    note: 57,
+    state: NoteState::Silent,
+    noise: NoteState::Silent,
+    started: 1,
+    held: false,
+    gain: 1.0,
+    brightness: 1.0,
+    velocity: 1.0,
+    layer: 0,
+  };
+  let mut params = FmParams::default();
+  params.operators[1].envelope = None;
+  let (mut dark, mut bright) = (FmVoice::default(), FmVoice::default());
+  let (freq, dt) = (0.01, 1.0 / 44100.0);
+  let mut modulated = 0f32;
+  for t in 0..1000 {
+    let sine = (TAU * freq * t as f32).sin();
+    // the brightness passed in wins over the one the note was played with
+    let x = dark.next(&voice, &params, freq, 0.0, dt, false);
+    assert!((x - sine).abs() < 1e-3, "{t}: {x}");
+    let x = bright.next(&voice, &params, freq, 1.0, dt, false);
+    modulated = modulated.max((x - sine).abs());
+  }
+  assert!(modulated > 0.1);
+}
@@ -11,8 +11,109 @@ pub fn lerp_as(t: f32, tmin: f32, tmax: f32, min: f32, max: f32) -> f32 {
   lerp(inv_lerp(t, tmin, tmax), min, max)
 }
 #[inline(always)]
-pub fn quadratic_interpolate_as(t: f32, tmin: f32, tmax: f32, min: f32, max: f32) -> f32 {
-  lerp(inv_lerp(t, tmin, tmax).powi(2), min, max)
+pub fn curve_as(curve: Curve, t: f32, tmin: f32, tmax: f32, min: f32, max: f32) -> f32 {
+  lerp(curve.apply(inv_lerp(t, tmin, tmax)), min, max)
+}
+
+/// How steeply [`Curve::Exponential`] bends: the share of the way left
+/// shrinks by a factor of e every `1 / EXP_STEEPNESS` of the segment.
+const EXP_STEEPNESS: f32 = 5.0;
+
+/// Path taken between two values, as the share of the way covered after a
+/// given share of the time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+  Linear,
+  /// Fast at first and settling slowly, like a capacitor charging.
+  Exponential,
+  /// Slow at first and fast at the end, the mirror image of `Exponential`.
+  Logarithmic,
+  /// Eases in and out.
+  SCurve,
+  /// Cubic bezier from 0 to 1 with the two inner control values given.
+  Bezier(f32, f32),
+}
+
+impl Curve {
+  pub const ALL: [Curve; 4] = [
+    Curve::Linear,
+    Curve::Exponential,
+    Curve::Logarithmic,
+    Curve::SCurve,
+  ];
+  /// `bezier:<a>:<b>` for the bezier, a plain word for the rest.
+  pub fn name(self) -> String {
+    match self {
+      Curve::Linear => "linear".into(),
+      Curve::Exponential => "exponential".into(),
+      Curve::Logarithmic => "logarithmic".into(),
+      Curve::SCurve => "s-curve".into(),
+      Curve::Bezier(a, b) => format!("bezier:{a}:{b}"),
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name.strip_prefix("bezier:") {
+      Some(points) => {
+        let (a, b) = points.split_once(':')?;
+        Some(Curve::Bezier(a.parse().ok()?, b.parse().ok()?))
+      }
+      None => Self::ALL.into_iter().find(|c| c.name() == name),
+    }
+  }
+  /// Share of the way covered at `x`, both from 0 to 1.
+  #[inline(always)]
+  pub fn apply(self, x: f32) -> f32 {
+    match self {
+      Curve::Linear => x,
+      Curve::Exponential => (1.0 - (-EXP_STEEPNESS * x).exp()) / (1.0 - (-EXP_STEEPNESS).exp()),
+      Curve::Logarithmic => 1.0 - Curve::Exponential.apply(1.0 - x),
+      Curve::SCurve => x * x * (3.0 - 2.0 * x),
+      Curve::Bezier(a, b) => {
+        let y = 1.0 - x;
+        3.0 * y * y * x * a + 3.0 * y * x * x * b + x * x * x
+      }
+    }
+  }
+  /// The `x` at which `apply` gives `share`, for curves that only go up.
+  pub fn inverse(self, share: f32) -> f32 {
+    match self {
+      Curve::Linear => share,
+      Curve::Exponential => -(1.0 - share * (1.0 - (-EXP_STEEPNESS).exp())).ln() / EXP_STEEPNESS,
+      Curve::Logarithmic => 1.0 - Curve::Exponential.inverse(1.0 - share),
+      Curve::SCurve => 0.5 - ((1.0 - 2.0 * share).asin() / 3.0).sin(),
+      Curve::Bezier(..) => {
+        // no closed form, so bisect
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..24 {
+          let mid = 0.5 * (low + high);
+          if self.apply(mid) < share {
+            low = mid;
+          } else {
+            high = mid;
+          }
+        }
+        0.5 * (low + high)
+      }
+    }
+  }
+}
+
+#[test]
+fn test_curves() {
+  for curve in Curve::ALL.into_iter().chain([Curve::Bezier(0.1, 0.9)]) {
+    assert!(curve.apply(0.0).abs() < 1e-6, "{curve:?}");
+    assert!((curve.apply(1.0) - 1.0).abs() < 1e-6, "{curve:?}");
+    for i in 1..10 {
+      let x = i as f32 / 10.0;
+      assert!(
+        (curve.inverse(curve.apply(x)) - x).abs() < 1e-4,
+        "{curve:?} at {x}"
+      );
+    }
+    assert_eq!(Curve::from_name(&curve.name()), Some(curve));
+  }
+  assert!(Curve::Exponential.apply(0.2) > 0.6);
+  assert!(Curve::Logarithmic.apply(0.8) < 0.4);
 }
 
 #[test]
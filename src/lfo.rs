@@ -0,0 +1,201 @@
+use crate::noise::Rng;
+use crate::voices::Voice;
+use crate::waves::NoteMode;
+use std::f32::consts::TAU;
+
+/// LFOs every engine runs.
+pub const LFO_COUNT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoShape {
+  Sine,
+  Saw,
+  Square,
+  Triangle,
+  /// A new random level every cycle, held until the next.
+  SampleHold,
+}
+
+impl LfoShape {
+  pub const ALL: [LfoShape; 5] = [
+    LfoShape::Sine,
+    LfoShape::Saw,
+    LfoShape::Square,
+    LfoShape::Triangle,
+    LfoShape::SampleHold,
+  ];
+  pub fn name(self) -> &'static str {
+    match self {
+      LfoShape::Sine => "sine",
+      LfoShape::Saw => "saw",
+      LfoShape::Square => "square",
+      LfoShape::Triangle => "triangle",
+      LfoShape::SampleHold => "random",
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|s| s.name() == name)
+  }
+  /// Shape of one of the plain waveform modes; the others have none.
+  pub fn from_mode(mode: NoteMode) -> Option<Self> {
+    match mode {
+      NoteMode::Sine => Some(LfoShape::Sine),
+      NoteMode::Saw => Some(LfoShape::Saw),
+      NoteMode::Square => Some(LfoShape::Square),
+      NoteMode::Triangle => Some(LfoShape::Triangle),
+      _ => None,
+    }
+  }
+  /// Level from -1 to 1 at `t` (in cycles), in phase with the notes of the
+  /// same mode: the sine and triangle start at 0 on the way up, the saw and
+  /// square start at 1. `held` is the sample-and-hold level.
+  pub fn value(self, t: f32, held: f32) -> f32 {
+    match self {
+      LfoShape::Sine => (TAU * t).sin(),
+      LfoShape::Saw => 1.0 - 2.0 * t,
+      LfoShape::Square => {
+        if t < 0.5 {
+          1.0
+        } else {
+          -1.0
+        }
+      }
+      LfoShape::Triangle => 4.0 * ((t + 0.75).fract() - 0.5).abs() - 1.0,
+      LfoShape::SampleHold => held,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LfoRate {
+  Hz(f32),
+  /// Length of a cycle in beats of [`crate::waves::WavesControl::tempo`].
+  Beats(f32),
+}
+
+impl LfoRate {
+  /// Hz as a plain number, beats with a `b` after it.
+  pub fn name(self) -> String {
+    match self {
+      LfoRate::Hz(hz) => format!("{hz}"),
+      LfoRate::Beats(beats) => format!("{beats}b"),
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name.strip_suffix('b') {
+      Some(beats) => beats.parse().ok().map(LfoRate::Beats),
+      None => name.parse().ok().map(LfoRate::Hz),
+    }
+  }
+  pub fn hz(self, tempo: f32) -> f32 {
+    match self {
+      LfoRate::Hz(hz) => hz,
+      LfoRate::Beats(beats) => tempo / 60.0 / beats.max(f32::EPSILON),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LfoParams {
+  pub shape: LfoShape,
+  pub rate: LfoRate,
+  /// Where in its cycle (0 to 1) the LFO starts, and restarts on a new note.
+  pub phase: f32,
+  /// Restart with every new note rather than run freely.
+  pub retrigger: bool,
+}
+
+impl Default for LfoParams {
+  fn default() -> Self {
+    Self {
+      shape: LfoShape::Sine,
+      rate: LfoRate::Hz(5.0),
+      phase: 0.0,
+      retrigger: false,
+    }
+  }
+}
+
+/// Running state of one LFO.
+#[derive(Debug, Clone, Copy, Default)]
+struct Lfo {
+  phase: f32,
+  held: f32,
+  running: bool,
+}
+
+impl Lfo {
+  fn next(&mut self, params: &LfoParams, step: f32, restart: bool, rng: &mut Rng) -> f32 {
+    if !self.running || restart {
+      self.running = true;
+      self.phase = params.phase.rem_euclid(1.0);
+      self.held = rng.bipolar();
+    }
+    let x = params.shape.value(self.phase, self.held);
+    let phase = self.phase + step;
+    if phase >= 1.0 {
+      self.held = rng.bipolar();
+    }
+    self.phase = phase.fract();
+    x
+  }
+}
+
+/// The LFOs of one engine, all stepped together.
+#[derive(Debug, Clone, Copy)]
+pub struct LfoBank {
+  lfos: [Lfo; LFO_COUNT],
+  /// `Voice::started` of the newest note seen, to retrigger on the next.
+  newest: u64,
+  rng: Rng,
+}
+
+impl Default for LfoBank {
+  fn default() -> Self {
+    Self {
+      lfos: [Lfo::default(); LFO_COUNT],
+      newest: 0,
+      rng: Rng::new(0x2545_F491),
+    }
+  }
+}
+
+impl LfoBank {
+  /// Every LFO at the current moment, then advances `dt` seconds.
+  pub fn next(
+    &mut self,
+    params: &[LfoParams; LFO_COUNT],
+    voices: &[Voice],
+    tempo: f32,
+    dt: f32,
+  ) -> [f32; LFO_COUNT] {
+    let newest = voices.iter().map(|v| v.started).max().unwrap_or(0);
+    let new_note = newest > self.newest;
+    self.newest = self.newest.max(newest);
+    let mut values = [0.0; LFO_COUNT];
+    for ((x, lfo), params) in values.iter_mut().zip(&mut self.lfos).zip(params) {
+      let step = params.rate.hz(tempo) * dt;
+      *x = lfo.next(params, step, params.retrigger && new_note, &mut self.rng);
+    }
+    values
+  }
+}
+
+#[test]
+fn test_lfo_shapes() {
+  for shape in [LfoShape::Sine, LfoShape::Saw, LfoShape::Triangle] {
+    // a quarter cycle in, every wave is at or near its peak like a sine
+    assert!(shape.value(0.25, 0.0) >= 0.5, "{shape:?}");
+  }
+  for (shape, start) in [
+    (LfoShape::Sine, 0.0),
+    (LfoShape::Triangle, 0.0),
+    (LfoShape::Saw, 1.0),
+    (LfoShape::Square, 1.0),
+  ] {
+    assert!((shape.value(0.0, 0.0) - start).abs() < 1e-6, "{shape:?}");
+  }
+  assert_eq!(LfoShape::Square.value(0.75, 0.0), -1.0);
+  assert_eq!(LfoShape::SampleHold.value(0.3, 0.4), 0.4);
+  assert_eq!(LfoRate::Beats(0.5).hz(120.0), 4.0);
+}
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+/// How far ahead the limiter looks, and so how late the output comes out.
+pub const LOOKAHEAD_SECS: f32 = 0.005;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimiterParams {
+  /// Highest level let out, as a linear amplitude.
+  pub ceiling: f32,
+  /// Seconds the gain takes to recover most of the way after a peak.
+  pub release: f32,
+}
+
+impl Default for LimiterParams {
+  fn default() -> Self {
+    Self {
+      ceiling: 0.9,
+      release: 0.1,
+    }
+  }
+}
+
+/// Stereo-linked look-ahead limiter. The gain starts going down before a
+/// peak arrives, so it never has to jump and the output never exceeds the
+/// ceiling.
+#[derive(Debug, Clone)]
+pub struct Limiter {
+  /// Samples waiting to come out, left and right.
+  delay: Box<[[f32; 2]]>,
+  /// Gains the box average is taken over, in the same ring order as `delay`.
+  smoothed: Box<[f32]>,
+  sum: f32,
+  pos: usize,
+  /// Increasing gains needed by the samples in the delay, oldest first, so
+  /// the front is the lowest. Indices count samples since the start.
+  needed: VecDeque<(u64, f32)>,
+  clock: u64,
+  /// Gain after the release, before it is averaged.
+  released: f32,
+  sample_rate: f32,
+}
+
+impl Limiter {
+  pub fn new(sample_rate: u32) -> Self {
+    let len = ((LOOKAHEAD_SECS * sample_rate as f32) as usize).max(1);
+    Self {
+      delay: vec![[0.0; 2]; len].into_boxed_slice(),
+      smoothed: vec![1.0; len].into_boxed_slice(),
+      sum: len as f32,
+      pos: 0,
+      needed: VecDeque::with_capacity(len + 1),
+      clock: 0,
+      released: 1.0,
+      sample_rate: sample_rate as f32,
+    }
+  }
+  /// Takes in one stereo sample and gives out the one from a look-ahead ago,
+  /// along with the gain it was scaled by.
+  pub fn process(&mut self, [l, r]: [f32; 2], params: &LimiterParams) -> ([f32; 2], f32) {
+    let len = self.delay.len();
+    let peak = l.abs().max(r.abs());
+    let need = if peak > params.ceiling {
+      params.ceiling / peak
+    } else {
+      1.0
+    };
+    // the lowest gain needed by this sample and any still in the delay, with
+    // the expired ones gone first so that no more than `len + 1` are kept
+    while self
+      .needed
+      .front()
+      .is_some_and(|(i, _)| *i + (len as u64) < self.clock)
+    {
+      self.needed.pop_front();
+    }
+    while self.needed.back().is_some_and(|(_, g)| *g >= need) {
+      self.needed.pop_back();
+    }
+    self.needed.push_back((self.clock, need));
+    self.clock += 1;
+    let lowest = self.needed.front().map_or(1.0, |(_, g)| *g);
+    let a = 1.0 - (-1.0 / (params.release.max(1e-3) * self.sample_rate)).exp();
+    self.released = lowest.min(self.released + a * (1.0 - self.released));
+    // every gain averaged here is already low enough for the sample coming out
+    self.sum += self.released - self.smoothed[self.pos];
+    self.smoothed[self.pos] = self.released;
+    let gain = (self.sum / len as f32).min(1.0);
+    let [dl, dr] = std::mem::replace(&mut self.delay[self.pos], [l, r]);
+    self.pos = (self.pos + 1) % len;
+    if self.pos == 0 {
+      // keep rounding errors from piling up in the running sum
+      self.sum = self.smoothed.iter().sum();
+    }
+    ([dl * gain, dr * gain], gain)
+  }
+}
+
+/// Gain as decibels, zero or below for a gain of one or less.
+pub fn gain_db(gain: f32) -> f32 {
+  20.0 * gain.max(1e-6).log10()
+}
+
+#[test]
+fn test_limiter() {
+  let params = LimiterParams::default();
+  let mut limiter = Limiter::new(8000);
+  let input = (0..400).map(|i| if (100..140).contains(&i) { 3.0 } else { 0.5 });
+  let mut peak = 0f32;
+  let mut lowest = 1f32;
+  for x in input {
+    let ([l, r], gain) = limiter.process([x, -x], &params);
+    assert_eq!(l, -r);
+    peak = peak.max(l.abs());
+    lowest = lowest.min(gain);
+  }
+  assert!(peak <= params.ceiling + 1e-5, "{peak}");
+  assert!((lowest - 0.3).abs() < 1e-3, "{lowest}");
+  // quiet signals pass untouched, a look-ahead late
+  let mut limiter = Limiter::new(8000);
+  let out: Vec<f32> = (0..80)
+    .map(|i| limiter.process([i as f32 / 100.0; 2], &params).0[0])
+    .collect();
+  assert_eq!(out[40], 0.0);
+  assert!((out[79] - 0.39).abs() < 1e-6);
+  // peaks that keep falling leave every needed gain in the queue, which
+  // still never grows past what was reserved for it
+  let mut limiter = Limiter::new(8000);
+  let capacity = limiter.needed.capacity();
+  for i in 0..400 {
+    limiter.process([10.0 - i as f32 / 100.0; 2], &params);
+  }
+  assert_eq!(limiter.needed.capacity(), capacity);
+}
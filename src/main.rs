@@ -3,31 +3,343 @@ use plotters::{
   style::{IntoFont, WHITE},
 };
 use rodio::{OutputStream, Sink};
+use std::sync::Arc;
 
 use crate::windows::WindowBackend;
 use crate::{
-  ui::{SawIcon, SineIcon, SquareIcon, TriangleIcon},
-  waves::{NoteMode, Waves},
+  envelope::EnvelopeParams,
+  fm::{FmAlgorithm, MAX_OPERATORS, MIN_OPERATORS},
+  lerp::Curve,
+  lfo::{LfoParams, LfoRate, LfoShape, LFO_COUNT},
+  limiter::LimiterParams,
+  midi::MidiInput,
+  modulation::{ModDestination, ModSource, Route, MOD_ENVELOPES},
+  noise::NoiseColor,
+  osc::Oscillators,
+  pan::PanLaw,
+  partials::load_partials,
+  pitch::VibratoParams,
+  preset::Preset,
+  ui::{CustomIcon, FmIcon, NoiseIcon, SawIcon, SineIcon, SquareIcon, TriangleIcon, WavetableIcon},
+  voices::{VelocityCurve, MAX_LAYERS, MAX_UNISON},
+  waves::{NoteMode, SynthWindow, Synthesis, Waves},
+  wavetable::Wavetable,
 };
 
 // pub mod fft;
+pub mod engine;
+pub mod envelope;
+pub mod filter;
+pub mod fm;
 pub mod lerp;
+pub mod lfo;
+pub mod limiter;
+pub mod midi;
+pub mod modulation;
+pub mod noise;
+pub mod osc;
+pub mod pan;
+pub mod partials;
+pub mod pitch;
+pub mod preset;
+pub mod queue;
 pub mod ui;
+pub mod voices;
 pub mod waves;
+pub mod wavetable;
 pub mod windows;
 
 fn main() {
   const LEN: usize = 44100 / 16;
   // const LEN: usize = 128;
-  let mut waves = Waves::new(LEN);
+  let sample_rate = arg("sample-rate").map_or(44100, |sr| {
+    sr.parse()
+      .unwrap_or_else(|_| panic!("invalid sample rate {sr:?}"))
+  });
+  let polyphony = arg("polyphony").map_or(32, |p| {
+    p.parse()
+      .unwrap_or_else(|_| panic!("invalid polyphony {p:?}"))
+  });
+  let waves = Waves::builder()
+    .sample_rate(sample_rate)
+    .polyphony(polyphony)
+    .auto_release(std::env::args().any(|arg| arg == "--auto-release"))
+    .frame_len(LEN)
+    .synthesis(Synthesis::OverlapAdd {
+      hop: LEN / 4,
+      window: SynthWindow::Hann,
+    })
+    .build()
+    .unwrap();
   const SIZE: (u32, u32) = (1920, 1080);
   let mut backend = WindowBackend::new(SIZE, waves.control());
   let control = waves.control();
   let (_stream, stream_handle) = OutputStream::try_default().unwrap();
   let sink = Sink::try_new(&stream_handle).unwrap();
-  let waves_clone = waves.shallow_clone();
-  sink.append(waves_clone);
+  match arg("engine").as_deref() {
+    Some("fft") | None => sink.append(waves),
+    Some("osc") => sink.append(Oscillators::new(waves.into_audio())),
+    Some(other) => panic!("unknown engine {other:?}, expected `fft` or `osc`"),
+  }
+  // the other flags then change the preset
+  if let Some(path) = arg("preset") {
+    Preset::load(&path)
+      .unwrap_or_else(|e| panic!("{path}: {e}"))
+      .apply(&control);
+  }
+  if let Some(width) = arg("pulse-width") {
+    let width = width
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid pulse width {width:?}"));
+    control.set_pulse_width(width);
+  }
+  if let Some(rate) = arg("pwm-rate") {
+    let rate = rate
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid pwm rate {rate:?}"));
+    let depth = arg("pwm-depth").map_or(0.25, |d| {
+      d.parse()
+        .unwrap_or_else(|_| panic!("invalid pwm depth {d:?}"))
+    });
+    control.set_pwm(depth, rate);
+  }
+  if let Some(path) = arg("partials") {
+    let partials = load_partials(&path).unwrap_or_else(|e| panic!("{path}: {e}"));
+    control.set_partials(partials);
+    control.set_mode(NoteMode::Custom);
+  }
+  let mut noise = control.noise();
+  if let Some(color) = arg("noise") {
+    noise.color = NoiseColor::from_name(&color)
+      .unwrap_or_else(|| panic!("unknown noise {color:?}, expected `white`, `pink` or `brown`"));
+  }
+  if let Some(layer) = arg("noise-layer") {
+    noise.layer = layer
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid noise layer {layer:?}"));
+  }
+  if let Some(bandwidth) = arg("noise-bandwidth") {
+    noise.bandwidth = bandwidth
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid noise bandwidth {bandwidth:?}"));
+  }
+  control.set_noise(noise);
+  if let Some(spec) = arg("noise-envelope") {
+    control.set_noise_envelope(parse_envelope(&spec));
+  }
+  let mut fm = control.fm();
+  if let Some(algorithm) = arg("fm") {
+    fm.algorithm = FmAlgorithm::from_name(&algorithm).unwrap_or_else(|| {
+      panic!(
+        "unknown fm algorithm {algorithm:?}, expected `stack`, `pairs`, `branch`, `two-stacks` or `parallel`"
+      )
+    });
+    control.set_mode(NoteMode::Fm);
+  }
+  if let Some(count) = arg("fm-operators") {
+    fm.count = count
+      .parse()
+      .ok()
+      .filter(|c| (MIN_OPERATORS..=MAX_OPERATORS).contains(c))
+      .unwrap_or_else(|| panic!("fm operators must be within {MIN_OPERATORS}..={MAX_OPERATORS}"));
+  }
+  if let Some(feedback) = arg("fm-feedback") {
+    fm.feedback = feedback
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid fm feedback {feedback:?}"));
+  }
+  control.set_fm(fm);
+  if let Some(path) = arg("wavetable") {
+    let frame_len = arg("wavetable-frame").map(|len| {
+      len
+        .parse()
+        .unwrap_or_else(|_| panic!("invalid wavetable frame length {len:?}"))
+    });
+    let table = Wavetable::load(&path, frame_len).unwrap_or_else(|e| panic!("{path}: {e}"));
+    println!("wavetable frames: {}", table.frame_count());
+    control.set_wavetable(table);
+    control.set_mode(NoteMode::Wavetable);
+  }
+  if let Some(position) = arg("table-position") {
+    let position = position
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid table position {position:?}"));
+    control.set_table_position(position);
+  }
+  // the first layer plays the mode set above, these go on top of it
+  for (i, spec) in args("layer").enumerate() {
+    let i = i + 1;
+    if i == MAX_LAYERS {
+      panic!("at most {} extra layers", MAX_LAYERS - 1);
+    }
+    let (mode, volume) = spec.split_once(':').unwrap_or((&spec, "1"));
+    let mut layer = control.layers()[i];
+    layer.enabled = true;
+    layer.mode = NoteMode::from_name(mode).unwrap_or_else(|| {
+      let names: Vec<_> = NoteMode::ALL.iter().map(|m| m.name()).collect();
+      panic!("unknown layer mode {mode:?}, expected one of {names:?}")
+    });
+    layer.volume = volume
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid layer volume {volume:?}"));
+    control.set_layer(i, layer);
+  }
+  if let Some(note) = arg("split") {
+    let note = note
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid split note {note:?}"));
+    control.set_split(Some(note));
+  }
+  if let Some(spec) = arg("adsr") {
+    control.set_envelope(0, parse_adsr(&spec));
+  }
+  if let Some(spec) = arg("envelope") {
+    control.set_envelope(0, parse_envelope(&spec));
+  }
+  // one curve per point, in order
+  if let Some(spec) = arg("envelope-curves") {
+    let curves: Vec<Curve> = spec
+      .split(',')
+      .map(|name| {
+        Curve::from_name(name).unwrap_or_else(|| {
+          let names: Vec<_> = Curve::ALL.iter().map(|c| c.name()).collect();
+          panic!("unknown curve {name:?}, expected one of {names:?} or `bezier:<a>:<b>`")
+        })
+      })
+      .collect();
+    let envelope = control.layers()[0].envelope;
+    control.set_envelope(0, envelope.with_curves(&curves));
+  }
+  for (i, spec) in args("mod-envelope").enumerate() {
+    if i == MOD_ENVELOPES {
+      panic!("at most {MOD_ENVELOPES} modulation envelopes");
+    }
+    control.set_mod_envelope(i, parse_envelope(&spec));
+  }
+  let mut unison = control.unison();
+  if let Some(voices) = arg("unison") {
+    unison.voices = voices
+      .parse()
+      .ok()
+      .filter(|v| (1..=MAX_UNISON).contains(v))
+      .unwrap_or_else(|| panic!("unison must be within 1..={MAX_UNISON}"));
+  }
+  if let Some(detune) = arg("unison-detune") {
+    unison.detune = detune
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid unison detune {detune:?}"));
+  }
+  if let Some(spread) = arg("unison-spread") {
+    unison.spread = spread
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid unison spread {spread:?}"));
+  }
+  if std::env::args().any(|arg| arg == "--unison-fixed-phase") {
+    unison.random_phase = false;
+  }
+  control.set_unison(unison);
+  let mut pan = control.panning();
+  if let Some(law) = arg("pan-law") {
+    pan.law = PanLaw::from_name(&law).unwrap_or_else(|| {
+      let names: Vec<_> = PanLaw::ALL.iter().map(|l| l.name()).collect();
+      panic!("unknown pan law {law:?}, expected one of {names:?}")
+    });
+  }
+  if let Some(spread) = arg("pan-spread") {
+    pan.spread = spread
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid pan spread {spread:?}"));
+  }
+  control.set_panning(pan);
+  let mut velocity = control.velocity();
+  if let Some(curve) = arg("velocity-curve") {
+    velocity.curve = VelocityCurve::from_name(&curve).unwrap_or_else(|| {
+      panic!(
+        "unknown velocity curve {curve:?}, expected `linear`, `exponential:<power>` or \
+         `fixed:<level>`"
+      )
+    });
+  }
+  if let Some(brightness) = arg("velocity-brightness") {
+    velocity.brightness = brightness
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid velocity brightness {brightness:?}"));
+  }
+  control.set_velocity(velocity);
+  // `<note>:<pan>`, with notes counted from 0 like `--split`
+  for spec in args("note-pan") {
+    let (note, position) = spec
+      .split_once(':')
+      .and_then(|(n, p)| Some((n.parse().ok()?, p.parse().ok()?)))
+      .unwrap_or_else(|| panic!("note pan {spec:?} should be `<note>:<pan>`"));
+    control.set_pan(note, position);
+  }
+  if let Some(range) = arg("bend-range") {
+    let range = range
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid bend range {range:?}"));
+    control.set_bend_range(range);
+  }
+  let defaults = control.vibrato();
+  let vibrato = |name: &str, default: f32| {
+    arg(name).map_or(default, |v| {
+      v.parse().unwrap_or_else(|_| panic!("invalid {name} {v:?}"))
+    })
+  };
+  control.set_vibrato(VibratoParams {
+    rate: vibrato("vibrato-rate", defaults.rate),
+    depth: vibrato("vibrato-depth", defaults.depth),
+    delay: vibrato("vibrato-delay", defaults.delay),
+  });
+  if let Some(tempo) = arg("tempo") {
+    let tempo = tempo
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid tempo {tempo:?}"));
+    control.set_tempo(tempo);
+  }
+  let mut filter = control.filter();
+  if let Some(cutoff) = arg("cutoff") {
+    filter.cutoff = cutoff
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid cutoff {cutoff:?}"));
+  }
+  if let Some(resonance) = arg("resonance") {
+    filter.resonance = resonance
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid resonance {resonance:?}"));
+  }
+  control.set_filter(filter);
+  for (i, spec) in args("lfo").enumerate() {
+    if i == LFO_COUNT {
+      panic!("at most {LFO_COUNT} LFOs");
+    }
+    let (lfo, destination, depth) = parse_lfo(&spec);
+    control.set_lfo(i, lfo);
+    control.add_route(Route::new(ModSource::Lfo(i), destination, depth));
+  }
+  for spec in args("route") {
+    control.add_route(parse_route(&spec));
+  }
+  if let Some(gain) = arg("master-gain") {
+    let gain = gain
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid master gain {gain:?}"));
+    control.set_master(gain);
+  }
+  let limiter = control.limiter();
+  control.set_limiter(LimiterParams {
+    ceiling: arg("limiter-ceiling").map_or(limiter.ceiling, |c| {
+      c.parse()
+        .unwrap_or_else(|_| panic!("invalid limiter ceiling {c:?}"))
+    }),
+    release: arg("limiter-release").map_or(limiter.release, |r| {
+      r.parse()
+        .unwrap_or_else(|_| panic!("invalid limiter release {r:?}"))
+    }),
+  });
   println!("max note: {}", control.max_note());
+  let midi = MidiInput::open_all(Arc::clone(&control));
+  println!("midi inputs: {}", midi.device_count());
 
   let (mut updater, backend) = backend.into_backend();
   let root = backend.into_drawing_area();
@@ -45,11 +357,11 @@ fn main() {
     if !updater.update() {
       return;
     }
-    buf.fill_with(|| waves.peek().unwrap());
+    control.scope(&mut buf);
     control.get_state(&mut freq);
     root.fill(&WHITE).unwrap();
     // let mouse = updater.1.mouse;
-    let mode = unsafe { *control.mode.get() };
+    let mode = control.layers()[updater.1.layer].mode;
     box_style.color = if mode == NoteMode::Sine {
       GREEN.into()
     } else {
@@ -78,7 +390,69 @@ fn main() {
     root
       .draw(&TriangleIcon::new((170, 5), 50, box_style))
       .unwrap();
-    // 5 60 115 170
+    box_style.color = if mode == NoteMode::Custom {
+      GREEN.into()
+    } else {
+      RED.into()
+    };
+    root
+      .draw(&CustomIcon::new((225, 5), 50, box_style))
+      .unwrap();
+    box_style.color = if mode == NoteMode::Noise {
+      GREEN.into()
+    } else {
+      RED.into()
+    };
+    root.draw(&NoiseIcon::new((280, 5), 50, box_style)).unwrap();
+    box_style.color = if mode == NoteMode::Fm {
+      GREEN.into()
+    } else {
+      RED.into()
+    };
+    root.draw(&FmIcon::new((335, 5), 50, box_style)).unwrap();
+    box_style.color = if mode == NoteMode::Wavetable {
+      GREEN.into()
+    } else {
+      RED.into()
+    };
+    root
+      .draw(&WavetableIcon::new((390, 5), 50, box_style))
+      .unwrap();
+    // 5 60 115 170 225 280 335 390
+    root
+      .draw(&Text::new(
+        format!(
+          "voices: {}/{}",
+          control.active_voices(),
+          control.polyphony()
+        ),
+        (450, 20),
+        ("sans-serif", 30).into_font(),
+      ))
+      .unwrap();
+    root
+      .draw(&Text::new(
+        format!("limiter: {:.1} dB", control.gain_reduction()),
+        (650, 20),
+        ("sans-serif", 30).into_font(),
+      ))
+      .unwrap();
+    let matrix = control.mod_matrix();
+    if let Some(route) = matrix.routes.get(updater.1.route) {
+      let (source, destination) = (route.source.name(), route.destination.name());
+      root
+        .draw(&Text::new(
+          format!(
+            "route {}/{}: {source} -> {destination} {:.2}",
+            updater.1.route + 1,
+            matrix.routes.len(),
+            route.depth
+          ),
+          (900, 20),
+          ("sans-serif", 30).into_font(),
+        ))
+        .unwrap();
+    }
     let mut chart = ChartBuilder::on(&root)
       .x_label_area_size(35)
       .y_label_area_size(40)
@@ -113,6 +487,110 @@ fn main() {
     updater.present();
   }
 }
+/// Value of a `--name=value` command line argument.
+fn arg(name: &str) -> Option<String> {
+  args(name).next()
+}
+
+/// Values of every `--name=value` command line argument, in order.
+fn args(name: &str) -> impl Iterator<Item = String> {
+  let prefix = format!("--{name}=");
+  std::env::args()
+    .skip(1)
+    .filter_map(move |arg| arg.strip_prefix(&prefix).map(String::from))
+}
+
+/// Reads `target:shape:rate:depth[:phase][:retrigger]`, where a rate ending in
+/// `b` is a cycle length in beats rather than Hz and the target is any
+/// modulation destination.
+fn parse_lfo(spec: &str) -> (LfoParams, ModDestination, f32) {
+  let fields: Vec<&str> = spec.split(':').collect();
+  let number = |field: &str| -> f32 {
+    field
+      .parse()
+      .unwrap_or_else(|_| panic!("invalid number {field:?} in lfo {spec:?}"))
+  };
+  let [target, shape, rate, depth, rest @ ..] = fields.as_slice() else {
+    panic!("lfo {spec:?} should be `target:shape:rate:depth[:phase][:retrigger]`");
+  };
+  let target = destination(target);
+  let mut lfo = LfoParams {
+    shape: LfoShape::from_name(shape).unwrap_or_else(|| {
+      let names: Vec<_> = LfoShape::ALL.iter().map(|s| s.name()).collect();
+      panic!("unknown lfo shape {shape:?}, expected one of {names:?}")
+    }),
+    rate: LfoRate::from_name(rate)
+      .unwrap_or_else(|| panic!("invalid rate {rate:?} in lfo {spec:?}")),
+    ..LfoParams::default()
+  };
+  for field in rest {
+    match *field {
+      "retrigger" => lfo.retrigger = true,
+      phase => lfo.phase = number(phase),
+    }
+  }
+  (lfo, target, number(depth))
+}
+
+/// Reads `attack:decay:sustain:release[:peak]`, with times in seconds and
+/// levels from 0 to 1.
+fn parse_adsr(spec: &str) -> EnvelopeParams {
+  let fields: Vec<f32> = spec
+    .split(':')
+    .map(|field| {
+      field
+        .parse()
+        .unwrap_or_else(|_| panic!("invalid number {field:?} in envelope {spec:?}"))
+    })
+    .collect();
+  let (attack, decay, sustain, release, peak) = match fields[..] {
+    [a, d, s, r] => (a, d, s, r, 1.0),
+    [a, d, s, r, peak] => (a, d, s, r, peak),
+    _ => panic!("envelope {spec:?} should be `attack:decay:sustain:release[:peak]`"),
+  };
+  EnvelopeParams::adsr(peak, attack, decay, sustain, release)
+    .unwrap_or_else(|e| panic!("envelope {spec:?}: {e}"))
+}
+
+/// Reads breakpoints as `level/secs[/curve],...[,sustain=<n>][,loop=<a>-<b>]`,
+/// or anything [`parse_adsr`] reads.
+fn parse_envelope(spec: &str) -> EnvelopeParams {
+  if !spec.contains('/') {
+    return parse_adsr(spec);
+  }
+  EnvelopeParams::from_name(spec).unwrap_or_else(|| {
+    panic!(
+      "envelope {spec:?} should be `level/secs[/curve],...[,sustain=<n>][,loop=<a>-<b>]`, \
+       ending at level 0"
+    )
+  })
+}
+
+/// Reads `source:destination:depth`, e.g. `velocity:amplitude:1` or
+/// `cc74:brightness:0.5`.
+fn parse_route(spec: &str) -> Route {
+  let [source, target, depth] = spec.split(':').collect::<Vec<_>>()[..] else {
+    panic!("route {spec:?} should be `source:destination:depth`");
+  };
+  let source = ModSource::from_name(source).unwrap_or_else(|| {
+    panic!(
+      "unknown modulation source {source:?}, expected `amp-envelope`, `envelope1`, `lfo1`, \
+       `velocity`, `key`, `mouse-x`, `mouse-y` or `cc<number>`"
+    )
+  });
+  let depth = depth
+    .parse()
+    .unwrap_or_else(|_| panic!("invalid depth {depth:?} in route {spec:?}"));
+  Route::new(source, destination(target), depth)
+}
+
+fn destination(name: &str) -> ModDestination {
+  ModDestination::from_name(name).unwrap_or_else(|| {
+    let names: Vec<_> = ModDestination::ALL.iter().map(|d| d.name()).collect();
+    panic!("unknown modulation destination {name:?}, expected one of {names:?}")
+  })
+}
+
 // #[test]
 // fn main_no_draw() {
 //   use winapi::um::winuser::GetMessageW;
@@ -121,7 +599,6 @@ fn main() {
 //   let (_stream, stream_handle) = OutputStream::try_default().unwrap();
 //   let sink = Sink::try_new(&stream_handle).unwrap();
 //   sink.append(waves);
-//   println!("max note: {}", control.max_note());
 
 //   create_window((800, 600));
 //   let mut msg = MSG {
@@ -0,0 +1,138 @@
+use crate::waves::WavesControl;
+use std::{ptr::null_mut, sync::Arc};
+use winapi::{
+  shared::{
+    basetsd::DWORD_PTR,
+    minwindef::{DWORD, UINT},
+  },
+  um::{
+    mmeapi::{midiInClose, midiInGetNumDevs, midiInOpen, midiInStart, midiInStop},
+    mmsystem::{CALLBACK_FUNCTION, HMIDIIN, MMSYSERR_NOERROR, MM_MIM_DATA},
+  },
+};
+
+/// MIDI note number of C0, the lowest note of the engine.
+const MIDI_C0: u8 = 12;
+const CC_SUSTAIN: u8 = 64;
+/// Pitch bend value of a wheel at rest.
+const BEND_CENTER: u16 = 8192;
+
+/// Channel voice message, decoded from the packed form the driver delivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiMessage {
+  NoteOn { note: u8, velocity: u8 },
+  NoteOff { note: u8 },
+  ControlChange { controller: u8, value: u8 },
+  /// 14-bit wheel position, centred on 8192.
+  PitchBend { value: u16 },
+}
+
+impl MidiMessage {
+  /// Status byte in the low byte, data bytes above it.
+  pub fn parse(packed: u32) -> Option<Self> {
+    let [status, data1, data2, _] = packed.to_le_bytes();
+    let (data1, data2) = (data1 & 0x7F, data2 & 0x7F);
+    match status & 0xF0 {
+      0x90 if data2 > 0 => Some(MidiMessage::NoteOn {
+        note: data1,
+        velocity: data2,
+      }),
+      // note-on with zero velocity is how running status spells note-off
+      0x80 | 0x90 => Some(MidiMessage::NoteOff { note: data1 }),
+      0xB0 => Some(MidiMessage::ControlChange {
+        controller: data1,
+        value: data2,
+      }),
+      0xE0 => Some(MidiMessage::PitchBend {
+        value: data1 as u16 | (data2 as u16) << 7,
+      }),
+      _ => None,
+    }
+  }
+  pub fn apply(self, control: &WavesControl) {
+    match self {
+      MidiMessage::NoteOn { note, velocity } if note >= MIDI_C0 => {
+        control.hit((note - MIDI_C0) as usize, velocity as f32 / 127.0);
+      }
+      MidiMessage::NoteOff { note } if note >= MIDI_C0 => {
+        control.release((note - MIDI_C0) as usize);
+      }
+      MidiMessage::ControlChange { controller, value } => {
+        if controller == CC_SUSTAIN {
+          control.set_pedal(value >= 64);
+        }
+        control.set_cc(controller, value as f32 / 127.0);
+      }
+      MidiMessage::PitchBend { value } => {
+        control.set_bend((value as f32 - BEND_CENTER as f32) / BEND_CENTER as f32);
+      }
+      _ => (),
+    }
+  }
+}
+
+/// Every MIDI input device on the system, feeding a [`WavesControl`].
+pub struct MidiInput {
+  handles: Vec<HMIDIIN>,
+  control: Arc<WavesControl>,
+}
+
+impl MidiInput {
+  /// Opens all devices that can be opened; the rest are skipped.
+  pub fn open_all(control: Arc<WavesControl>) -> Self {
+    let mut handles = vec![];
+    let instance = Arc::as_ptr(&control) as DWORD_PTR;
+    for device in 0..unsafe { midiInGetNumDevs() } {
+      let mut handle: HMIDIIN = null_mut();
+      let res = unsafe {
+        midiInOpen(
+          &mut handle,
+          device,
+          midi_proc as DWORD_PTR,
+          instance,
+          CALLBACK_FUNCTION,
+        )
+      };
+      if res != MMSYSERR_NOERROR {
+        continue;
+      }
+      unsafe { midiInStart(handle) };
+      handles.push(handle);
+    }
+    Self { handles, control }
+  }
+  pub fn device_count(&self) -> usize {
+    self.handles.len()
+  }
+  pub fn control(&self) -> Arc<WavesControl> {
+    Arc::clone(&self.control)
+  }
+}
+
+impl Drop for MidiInput {
+  fn drop(&mut self) {
+    // closing waits for the callbacks, so `control` outlives them
+    for &handle in &self.handles {
+      unsafe {
+        midiInStop(handle);
+        midiInClose(handle);
+      }
+    }
+  }
+}
+
+unsafe extern "system" fn midi_proc(
+  _handle: HMIDIIN,
+  msg: UINT,
+  instance: DWORD_PTR,
+  param1: DWORD_PTR,
+  _param2: DWORD_PTR,
+) {
+  if msg != MM_MIM_DATA {
+    return;
+  }
+  let control = &*(instance as *const WavesControl);
+  if let Some(message) = MidiMessage::parse(param1 as DWORD) {
+    message.apply(control);
+  }
+}
@@ -0,0 +1,413 @@
+use crate::envelope::{ControlClock, EnvelopeParams, NoteState};
+use crate::lfo::LFO_COUNT;
+use crate::voices::Voice;
+
+/// Envelopes every voice runs besides its own, only to modulate with.
+pub const MOD_ENVELOPES: usize = 2;
+/// Key tracking is zero at this note (C4), counting semitones up from C0.
+const KEY_TRACK_CENTER: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModSource {
+  /// The note's own envelope, from 0 to 1.
+  AmpEnvelope,
+  /// One of the [`MOD_ENVELOPES`], from 0 to 1.
+  Envelope(usize),
+  /// One of the LFOs, from -1 to 1.
+  Lfo(usize),
+  /// As played, from 0 to 1.
+  Velocity,
+  /// Octaves above C4, negative below it.
+  Key,
+  /// From 0 at the left of the window to 1 at the right.
+  MouseX,
+  /// From 0 at the bottom of the window to 1 at the top.
+  MouseY,
+  /// A MIDI controller, from 0 to 1.
+  Cc(u8),
+}
+
+impl ModSource {
+  /// Whether the source swings both ways around zero.
+  pub fn is_bipolar(self) -> bool {
+    matches!(self, ModSource::Lfo(_) | ModSource::Key)
+  }
+  pub fn value(self, sources: &ModSources) -> f32 {
+    match self {
+      ModSource::AmpEnvelope => sources.envelope,
+      ModSource::Envelope(i) => sources.envelopes.get(i).copied().unwrap_or(0.0),
+      ModSource::Lfo(i) => sources.lfos.get(i).copied().unwrap_or(0.0),
+      ModSource::Velocity => sources.velocity,
+      ModSource::Key => sources.key,
+      ModSource::MouseX => sources.inputs.mouse[0],
+      ModSource::MouseY => sources.inputs.mouse[1],
+      ModSource::Cc(cc) => sources.inputs.cc[cc as usize & 0x7F],
+    }
+  }
+  /// Envelopes and controllers are numbered from 1.
+  pub fn name(self) -> String {
+    match self {
+      ModSource::AmpEnvelope => "amp-envelope".into(),
+      ModSource::Envelope(i) => format!("envelope{}", i + 1),
+      ModSource::Lfo(i) => format!("lfo{}", i + 1),
+      ModSource::Velocity => "velocity".into(),
+      ModSource::Key => "key".into(),
+      ModSource::MouseX => "mouse-x".into(),
+      ModSource::MouseY => "mouse-y".into(),
+      ModSource::Cc(cc) => format!("cc{cc}"),
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    let numbered = |prefix: &str, count: usize| {
+      let i: usize = name.strip_prefix(prefix)?.parse().ok()?;
+      (1..=count).contains(&i).then_some(i - 1)
+    };
+    Some(match name {
+      "amp-envelope" => ModSource::AmpEnvelope,
+      "velocity" => ModSource::Velocity,
+      "key" => ModSource::Key,
+      "mouse-x" => ModSource::MouseX,
+      "mouse-y" => ModSource::MouseY,
+      _ => {
+        if let Some(i) = numbered("envelope", MOD_ENVELOPES) {
+          ModSource::Envelope(i)
+        } else if let Some(i) = numbered("lfo", LFO_COUNT) {
+          ModSource::Lfo(i)
+        } else {
+          let cc: u8 = name.strip_prefix("cc")?.parse().ok()?;
+          ModSource::Cc((cc < 128).then_some(cc)?)
+        }
+      }
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModDestination {
+  /// Dips the level by up to `depth` of it.
+  Amplitude,
+  /// Cents.
+  Pitch,
+  /// Share of a cycle added to the width of [`crate::waves::NoteMode::Square`].
+  PulseWidth,
+  /// Added to the share of harmonics a note is rendered with.
+  Brightness,
+  /// Added to the note's position, -1 at the left to 1 at the right.
+  Pan,
+  /// Octaves the filter cutoff moves.
+  Cutoff,
+  /// Added to the Q of the filter.
+  Resonance,
+}
+
+impl ModDestination {
+  pub const ALL: [ModDestination; 7] = [
+    ModDestination::Amplitude,
+    ModDestination::Pitch,
+    ModDestination::PulseWidth,
+    ModDestination::Brightness,
+    ModDestination::Pan,
+    ModDestination::Cutoff,
+    ModDestination::Resonance,
+  ];
+  /// Whether the destination is shared by every voice, like the filter is.
+  /// Per-voice sources are then taken from the newest note.
+  pub fn is_global(self) -> bool {
+    matches!(self, ModDestination::Cutoff | ModDestination::Resonance)
+  }
+  pub fn name(self) -> &'static str {
+    match self {
+      ModDestination::Amplitude => "amplitude",
+      ModDestination::Pitch => "pitch",
+      ModDestination::PulseWidth => "pulse-width",
+      ModDestination::Brightness => "brightness",
+      ModDestination::Pan => "pan",
+      ModDestination::Cutoff => "cutoff",
+      ModDestination::Resonance => "resonance",
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|d| d.name() == name)
+  }
+}
+
+/// One line of the matrix: `source` moves `destination` by `depth` of its
+/// units at full swing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+  pub source: ModSource,
+  pub destination: ModDestination,
+  pub depth: f32,
+}
+
+impl Route {
+  pub fn new(source: ModSource, destination: ModDestination, depth: f32) -> Self {
+    Self {
+      source,
+      destination,
+      depth,
+    }
+  }
+}
+
+/// Every route in use, swapped whole when edited.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModMatrix {
+  pub routes: Vec<Route>,
+}
+
+impl ModMatrix {
+  /// Whether any route with a depth moves `destination`.
+  pub fn routes_to(&self, destination: ModDestination) -> bool {
+    self
+      .routes
+      .iter()
+      .any(|r| r.destination == destination && r.depth != 0.0)
+  }
+  /// Sum of the routes to the destinations that are `global` or not.
+  pub fn apply(&self, sources: &ModSources, global: bool) -> Modulation {
+    let mut modulation = Modulation::default();
+    for route in &self.routes {
+      if route.depth != 0.0 && route.destination.is_global() == global {
+        modulation.add(route, route.source.value(sources));
+      }
+    }
+    modulation
+  }
+  /// The gain of [`Self::apply`] alone, for following the amplitude routes
+  /// more often than the rest.
+  pub fn gain(&self, sources: &ModSources) -> f32 {
+    let mut modulation = Modulation::default();
+    let routes = self.routes.iter();
+    for route in routes.filter(|r| r.destination == ModDestination::Amplitude && r.depth != 0.0) {
+      modulation.add(route, route.source.value(sources));
+    }
+    modulation.gain
+  }
+}
+
+/// Inputs from outside the synth, written by the window and MIDI threads.
+#[derive(Debug, Clone, Copy)]
+pub struct ModInputs {
+  pub mouse: [f32; 2],
+  pub cc: [f32; 128],
+}
+
+impl Default for ModInputs {
+  fn default() -> Self {
+    Self {
+      mouse: [0.0; 2],
+      cc: [0.0; 128],
+    }
+  }
+}
+
+/// Value of every source for one voice at one moment.
+#[derive(Debug, Clone, Copy)]
+pub struct ModSources<'a> {
+  pub envelope: f32,
+  pub envelopes: [f32; MOD_ENVELOPES],
+  pub lfos: &'a [f32; LFO_COUNT],
+  pub velocity: f32,
+  pub key: f32,
+  pub inputs: &'a ModInputs,
+}
+
+impl<'a> ModSources<'a> {
+  /// With no voice, so the per-voice sources are all zero.
+  pub fn global(lfos: &'a [f32; LFO_COUNT], inputs: &'a ModInputs) -> Self {
+    Self {
+      envelope: 0.0,
+      envelopes: [0.0; MOD_ENVELOPES],
+      lfos,
+      velocity: 0.0,
+      key: 0.0,
+      inputs,
+    }
+  }
+  /// For `voice`, whose envelope is at `envelope` of its peak.
+  pub fn voice(self, voice: &Voice, envelope: f32, envelopes: [f32; MOD_ENVELOPES]) -> Self {
+    Self {
+      envelope,
+      envelopes,
+      velocity: voice.velocity,
+      key: (voice.note as f32 - KEY_TRACK_CENTER as f32) / 12.0,
+      ..self
+    }
+  }
+}
+
+/// What the routes add up to, ready to apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Modulation {
+  /// Multiplies the level.
+  pub gain: f32,
+  pub cents: f32,
+  pub pulse_width: f32,
+  pub brightness: f32,
+  pub pan: f32,
+  /// Octaves.
+  pub cutoff: f32,
+  pub resonance: f32,
+}
+
+impl Default for Modulation {
+  fn default() -> Self {
+    Self {
+      gain: 1.0,
+      cents: 0.0,
+      pulse_width: 0.0,
+      brightness: 0.0,
+      pan: 0.0,
+      cutoff: 0.0,
+      resonance: 0.0,
+    }
+  }
+}
+
+impl Modulation {
+  /// Adds `route` with its source at `x`.
+  pub fn add(&mut self, route: &Route, x: f32) {
+    let depth = route.depth;
+    match route.destination {
+      // peaks at full level, so modulation never makes a note louder
+      ModDestination::Amplitude => {
+        let x = if route.source.is_bipolar() {
+          (x + 1.0) / 2.0
+        } else {
+          x
+        };
+        self.gain *= (1.0 - depth * (1.0 - x)).max(0.0);
+      }
+      ModDestination::Pitch => self.cents += depth * x,
+      ModDestination::PulseWidth => self.pulse_width += depth * x,
+      ModDestination::Brightness => self.brightness += depth * x,
+      ModDestination::Pan => self.pan += depth * x,
+      ModDestination::Cutoff => self.cutoff += depth * x,
+      ModDestination::Resonance => self.resonance += depth * x,
+    }
+  }
+  /// Takes the global destinations from `global`.
+  pub fn with_global(self, global: &Modulation) -> Self {
+    Self {
+      cutoff: global.cutoff,
+      resonance: global.resonance,
+      ..self
+    }
+  }
+}
+
+/// Runs a voice's [`MOD_ENVELOPES`], started and released with its note.
+#[derive(Debug, Clone, Copy)]
+pub struct ModVoice {
+  envelopes: [NoteState; MOD_ENVELOPES],
+  /// `Voice::started` of the note the envelopes belong to.
+  started: u64,
+  released: bool,
+}
+
+impl Default for ModVoice {
+  fn default() -> Self {
+    Self {
+      envelopes: [NoteState::Silent; MOD_ENVELOPES],
+      started: 0,
+      released: true,
+    }
+  }
+}
+
+impl ModVoice {
+  /// Levels of the envelopes, each peaking at one, then advances `dt`.
+  pub fn next(
+    &mut self,
+    voice: &Voice,
+    params: &[EnvelopeParams; MOD_ENVELOPES],
+    dt: f32,
+    sustain: bool,
+  ) -> [f32; MOD_ENVELOPES] {
+    self.follow(voice, params);
+    let mut levels = [0.0; MOD_ENVELOPES];
+    for ((level, env), envelope) in levels.iter_mut().zip(&mut self.envelopes).zip(params) {
+      *level = env.peek(envelope) / envelope.peak();
+      if dt > 0.0 {
+        env.next(envelope, dt, sustain);
+      }
+    }
+    levels
+  }
+  /// Fills `levels` with every envelope sample by sample, each peaking at
+  /// one, as [`NoteState::render`] does. Returns the first levels.
+  pub fn render(
+    &mut self,
+    voice: &Voice,
+    params: &[EnvelopeParams; MOD_ENVELOPES],
+    sustain: bool,
+    clock: &ControlClock,
+    commit: usize,
+    levels: &mut [Box<[f32]>; MOD_ENVELOPES],
+  ) -> [f32; MOD_ENVELOPES] {
+    self.follow(voice, params);
+    let mut first = [0.0; MOD_ENVELOPES];
+    let envelopes = self.envelopes.iter_mut().zip(params);
+    for ((first, levels), (env, envelope)) in first.iter_mut().zip(levels).zip(envelopes) {
+      env.render(envelope, sustain, clock, commit, levels);
+      let peak = envelope.peak();
+      levels.iter_mut().for_each(|level| *level /= peak);
+      *first = levels[0];
+    }
+    first
+  }
+  /// Starts the envelopes with a new note of `voice` and releases them with it.
+  fn follow(&mut self, voice: &Voice, params: &[EnvelopeParams; MOD_ENVELOPES]) {
+    if voice.started != self.started {
+      self.started = voice.started;
+      self.released = false;
+      for (env, envelope) in self.envelopes.iter_mut().zip(params) {
+        *env = env.retrigger(envelope);
+      }
+    }
+    if !self.released && voice.state.is_releasing() {
+      self.released = true;
+      for (env, envelope) in self.envelopes.iter_mut().zip(params) {
+        *env = env.release(envelope);
+      }
+    }
+  }
+}
+
+#[test]
+fn test_mod_matrix() {
+  for name in [
+    "amp-envelope",
+    "envelope2",
+    "lfo1",
+    "key",
+    "mouse-y",
+    "cc74",
+  ] {
+    assert_eq!(ModSource::from_name(name).unwrap().name(), name);
+  }
+  assert_eq!(ModSource::from_name("lfo9"), None);
+  assert_eq!(ModSource::from_name("cc128"), None);
+
+  let matrix = ModMatrix {
+    routes: vec![
+      Route::new(ModSource::Velocity, ModDestination::Amplitude, 1.0),
+      Route::new(ModSource::Lfo(0), ModDestination::Pitch, 50.0),
+      Route::new(ModSource::MouseX, ModDestination::Cutoff, 2.0),
+    ],
+  };
+  let inputs = ModInputs {
+    mouse: [0.5, 0.0],
+    ..ModInputs::default()
+  };
+  let lfos = [1.0, 0.0, 0.0, 0.0];
+  let sources = ModSources {
+    velocity: 0.25,
+    ..ModSources::global(&lfos, &inputs)
+  };
+  let voice = matrix.apply(&sources, false);
+  assert_eq!((voice.gain, voice.cents, voice.cutoff), (0.25, 50.0, 0.0));
+  assert_eq!(matrix.gain(&sources), voice.gain);
+  assert_eq!(matrix.apply(&sources, true).cutoff, 1.0);
+}
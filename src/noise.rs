@@ -0,0 +1,254 @@
+use std::f32::consts::{LN_2, TAU};
+
+use num::Complex;
+
+/// Spectral tilt of a noise source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseColor {
+  /// Equal power at every frequency.
+  White,
+  /// Equal power in every octave, falling 3 dB per octave.
+  Pink,
+  /// Falling 6 dB per octave, like a random walk.
+  Brown,
+}
+
+impl NoiseColor {
+  pub const ALL: [NoiseColor; 3] = [NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown];
+  pub fn name(self) -> &'static str {
+    match self {
+      NoiseColor::White => "white",
+      NoiseColor::Pink => "pink",
+      NoiseColor::Brown => "brown",
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|c| c.name() == name)
+  }
+  /// Amplitude falls off as `f^-slope`.
+  pub fn slope(self) -> f32 {
+    match self {
+      NoiseColor::White => 0.0,
+      NoiseColor::Pink => 0.5,
+      NoiseColor::Brown => 1.0,
+    }
+  }
+}
+
+/// Noise heard through [`crate::waves::NoteMode::Noise`] and the noise layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NoiseParams {
+  pub color: NoiseColor,
+  /// Width in octaves of the band a noise note covers, centred on its pitch.
+  pub bandwidth: f32,
+  /// Level of the full-band noise mixed under every tone; 0 turns it off.
+  pub layer: f32,
+}
+
+impl Default for NoiseParams {
+  fn default() -> Self {
+    Self {
+      color: NoiseColor::Pink,
+      bandwidth: 1.0,
+      layer: 0.0,
+    }
+  }
+}
+
+impl NoiseParams {
+  /// Lower and upper edge of the band around `freq`.
+  pub fn band(&self, freq: f32) -> (f32, f32) {
+    let half = 2f32.powf(self.bandwidth / 2.0);
+    (freq / half, freq * half)
+  }
+}
+
+/// Xorshift generator; plenty for audio and cheap enough to run per sample.
+#[derive(Debug, Clone, Copy)]
+pub struct Rng(u32);
+
+impl Rng {
+  pub fn new(seed: u32) -> Self {
+    // zero is the one state xorshift never leaves
+    Self(seed.max(1))
+  }
+  pub fn next_u32(&mut self) -> u32 {
+    let mut x = self.0;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    self.0 = x;
+    x
+  }
+  /// Uniform in `[0, 1)`.
+  pub fn unit(&mut self) -> f32 {
+    (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+  }
+  /// Uniform in `[-1, 1)`, with a variance of 1/3.
+  pub fn bipolar(&mut self) -> f32 {
+    2.0 * self.unit() - 1.0
+  }
+  /// Uniform in `[0, TAU)`.
+  pub fn phase(&mut self) -> f32 {
+    TAU * self.unit()
+  }
+}
+
+/// Variance of [`Rng::bipolar`].
+const WHITE_VARIANCE: f32 = 1.0 / 3.0;
+
+/// Paul Kellet's pink filter: one-pole sections given as `(pole, gain)` for a
+/// 44.1 kHz stream, plus the share of the input passed straight through.
+const PINK_POLES: [(f32, f32); 3] = [(0.99765, 0.099046), (0.963, 0.2965164), (0.57, 1.0526913)];
+const PINK_DIRECT: f32 = 0.1848;
+const PINK_SAMPLE_RATE: f32 = 44100.0;
+/// Below this brown noise flattens out instead of growing without bound.
+const BROWN_CORNER_HZ: f32 = 20.0;
+
+/// Colours white noise in the time domain with a sum of one-pole lowpasses.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorFilter {
+  color: NoiseColor,
+  poles: [(f32, f32); 3],
+  direct: f32,
+  state: [f32; 3],
+}
+
+impl ColorFilter {
+  pub fn new(color: NoiseColor, sample_rate: u32) -> Self {
+    let sample_rate = sample_rate as f32;
+    let (poles, direct) = match color {
+      NoiseColor::White => ([(0.0, 0.0); 3], 1.0),
+      // keep the pole frequencies where they are at other sample rates
+      NoiseColor::Pink => (
+        PINK_POLES.map(|(p, g)| (p.powf(PINK_SAMPLE_RATE / sample_rate), g)),
+        PINK_DIRECT,
+      ),
+      NoiseColor::Brown => {
+        let p = (-TAU * BROWN_CORNER_HZ / sample_rate).exp();
+        ([(p, 1.0 - p), (0.0, 0.0), (0.0, 0.0)], 0.0)
+      }
+    };
+    Self {
+      color,
+      poles,
+      direct,
+      state: [0.0; 3],
+    }
+  }
+  pub fn color(&self) -> NoiseColor {
+    self.color
+  }
+  pub fn process(&mut self, x: f32) -> f32 {
+    let mut y = self.direct * x;
+    for (s, (p, g)) in self.state.iter_mut().zip(self.poles) {
+      *s = p * *s + g * x;
+      y += *s;
+    }
+    y
+  }
+  /// Output variance for a unit-variance white input: the sum of the squared
+  /// impulse response, which has a closed form for one-pole sections.
+  pub fn power_gain(&self) -> f32 {
+    let first = self.direct + self.poles.iter().map(|(_, g)| g).sum::<f32>();
+    let mut tail = 0.0;
+    for (pi, gi) in self.poles {
+      for (pj, gj) in self.poles {
+        tail += gi * gj * pi * pj / (1.0 - pi * pj);
+      }
+    }
+    first * first + tail
+  }
+  /// Magnitude response at `freq`, in cycles per sample.
+  pub fn response(&self, freq: f32) -> f32 {
+    let z = Complex::cis(-TAU * freq);
+    let h = self
+      .poles
+      .iter()
+      .fold(Complex::new(self.direct, 0.0), |h, &(p, g)| {
+        h + g / (1.0 - z * p)
+      });
+    h.norm()
+  }
+}
+
+/// Noise narrowed to a band around a note, for the time-domain engine: white
+/// noise through a [`ColorFilter`] and a constant-peak bandpass, scaled to
+/// the RMS of a unit sine.
+#[derive(Debug, Clone, Copy)]
+pub struct BandNoise {
+  color: ColorFilter,
+  /// Frequency and width the coefficients were computed for.
+  key: (f32, f32),
+  b0: f32,
+  a1: f32,
+  a2: f32,
+  scale: f32,
+  x: [f32; 2],
+  y: [f32; 2],
+}
+
+impl BandNoise {
+  pub fn new(sample_rate: u32) -> Self {
+    Self {
+      color: ColorFilter::new(NoiseColor::White, sample_rate),
+      key: (0.0, 0.0),
+      b0: 0.0,
+      a1: 0.0,
+      a2: 0.0,
+      scale: 0.0,
+      x: [0.0; 2],
+      y: [0.0; 2],
+    }
+  }
+  /// Next sample of noise around `freq` (cycles per sample), drawn from `rng`.
+  pub fn next(&mut self, rng: &mut Rng, params: &NoiseParams, freq: f32, sample_rate: u32) -> f32 {
+    if self.color.color() != params.color {
+      self.color = ColorFilter::new(params.color, sample_rate);
+      self.key = (0.0, 0.0);
+    }
+    if self.key != (freq, params.bandwidth) {
+      self.tune(freq, params.bandwidth);
+    }
+    let x = self.color.process(rng.bipolar());
+    let y = self.b0 * (x - self.x[1]) - self.a1 * self.y[0] - self.a2 * self.y[1];
+    self.x = [x, self.x[0]];
+    self.y = [y, self.y[0]];
+    self.scale * y
+  }
+  fn tune(&mut self, freq: f32, bandwidth: f32) {
+    let w0 = TAU * freq.min(0.49);
+    let alpha = w0.sin() * (LN_2 / 2.0 * bandwidth.max(0.01) * w0 / w0.sin()).sinh();
+    let a0 = 1.0 + alpha;
+    self.b0 = alpha / a0;
+    self.a1 = -2.0 * w0.cos() / a0;
+    self.a2 = (1.0 - alpha) / a0;
+    // the bandpass passes `alpha / (1 + alpha)` of white noise's power, and the
+    // colour is close to flat across the band at the level it has in the middle
+    let density = WHITE_VARIANCE * self.color.response(freq).powi(2);
+    self.scale = (0.5 / (density * self.b0)).sqrt();
+    self.key = (freq, bandwidth);
+  }
+}
+
+/// Full-band coloured noise for the time-domain engine, at the RMS of a unit
+/// sine.
+#[derive(Debug, Clone, Copy)]
+pub struct LayerNoise {
+  color: ColorFilter,
+  scale: f32,
+}
+
+impl LayerNoise {
+  pub fn new(color: NoiseColor, sample_rate: u32) -> Self {
+    let color = ColorFilter::new(color, sample_rate);
+    let scale = (0.5 / (WHITE_VARIANCE * color.power_gain())).sqrt();
+    Self { color, scale }
+  }
+  pub fn next(&mut self, rng: &mut Rng, color: NoiseColor, sample_rate: u32) -> f32 {
+    if self.color.color() != color {
+      *self = Self::new(color, sample_rate);
+    }
+    self.scale * self.color.process(rng.bipolar())
+  }
+}
@@ -0,0 +1,328 @@
+use crate::engine::AudioState;
+use crate::filter::{Svf, SvfCoefs};
+use crate::fm::FmVoice;
+use crate::lfo::LfoBank;
+use crate::limiter::Limiter;
+use crate::modulation::{ModSources, ModVoice};
+use crate::noise::{BandNoise, LayerNoise, Rng};
+use crate::partials::Partial;
+use crate::pitch::{pitch_ratio, BendGlide};
+use crate::voices::MAX_UNISON;
+use crate::waves::{note_freq, NoteMode, WavesControl, PULSE_WIDTH_RANGE};
+use crate::wavetable::Wavetable;
+use rodio::Source;
+use std::{
+  f32::consts::{PI, TAU},
+  sync::Arc,
+};
+
+/// Samples between two looks the UI gets at the voices.
+const PUBLISH_INTERVAL: usize = 512;
+
+/// Time-domain engine: one phase accumulator per unison copy, rendered sample by
+/// sample with polyBLEP band limiting. Shares its control surface with
+/// [`crate::waves::Waves`], so either engine can be fed to the sink.
+pub struct Oscillators {
+  phases: Box<[[f32; MAX_UNISON]]>,
+  integrators: Box<[[f32; MAX_UNISON]]>,
+  filters: Box<[[f32; MAX_UNISON]]>,
+  /// `Voice::started` of the note last seen, to spot new notes.
+  started: Box<[u64]>,
+  /// Seconds since the note started, for the vibrato delay.
+  ages: Box<[f32]>,
+  bands: Box<[BandNoise]>,
+  layers: Box<[LayerNoise]>,
+  fm_voices: Box<[[FmVoice; MAX_UNISON]]>,
+  mod_voices: Box<[ModVoice]>,
+  rng: Rng,
+  pwm_phase: f32,
+  bend: BendGlide,
+  lfos: LfoBank,
+  /// Left and right.
+  lowpass: [Svf; 2],
+  limiter: Limiter,
+  right: Option<f32>,
+  /// Samples left until the voices are next shown to the UI.
+  until_publish: usize,
+  audio: AudioState,
+}
+
+impl Oscillators {
+  /// Takes over the voices and commands of `audio`, which can come from
+  /// [`crate::waves::Waves::into_audio`].
+  pub fn new(audio: AudioState) -> Self {
+    let len = audio.voices.polyphony();
+    let sample_rate = audio.control().sample_rate;
+    let color = audio.params.noise.color;
+    Self {
+      phases: vec![[0.0; MAX_UNISON]; len].into_boxed_slice(),
+      integrators: vec![[0.0; MAX_UNISON]; len].into_boxed_slice(),
+      filters: vec![[0.0; MAX_UNISON]; len].into_boxed_slice(),
+      started: vec![0; len].into_boxed_slice(),
+      ages: vec![0.0; len].into_boxed_slice(),
+      bands: vec![BandNoise::new(sample_rate); len].into_boxed_slice(),
+      layers: vec![LayerNoise::new(color, sample_rate); len].into_boxed_slice(),
+      fm_voices: vec![[FmVoice::default(); MAX_UNISON]; len].into_boxed_slice(),
+      mod_voices: vec![ModVoice::default(); len].into_boxed_slice(),
+      rng: Rng::new(0x9E37_79B9),
+      pwm_phase: 0.0,
+      bend: BendGlide::default(),
+      lfos: LfoBank::default(),
+      lowpass: [Svf::default(); 2],
+      limiter: Limiter::new(sample_rate),
+      right: None,
+      until_publish: 0,
+      audio,
+    }
+  }
+  pub fn control(&self) -> Arc<WavesControl> {
+    Arc::clone(self.audio.control())
+  }
+}
+
+impl Oscillators {
+  /// Next interleaved sample, left channel first.
+  pub fn calc(&mut self) -> f32 {
+    if let Some(r) = self.right.take() {
+      return r;
+    }
+    self.audio.update();
+    if self.until_publish == 0 {
+      self.audio.publish();
+      self.until_publish = PUBLISH_INTERVAL;
+    }
+    self.until_publish -= 1;
+    let rate = self.audio.control().sample_rate;
+    let AudioState {
+      voices,
+      params: settings,
+      sustain,
+      ..
+    } = &mut self.audio;
+    let sustain = *sustain;
+    let layers = &settings.layers;
+    let sample_rate = rate as f32;
+    let dt = 1.0 / sample_rate;
+    let pulse = settings.pulse;
+    self.pwm_phase = (self.pwm_phase + pulse.pwm_rate * dt).fract();
+    let lfos = self
+      .lfos
+      .next(&settings.lfos, voices.voices(), settings.tempo, dt);
+    let matrix = &settings.mod_matrix;
+    let mod_envelopes = settings.mod_envelopes;
+    let sources = ModSources::global(&lfos, &settings.mod_inputs);
+    let mut newest = (0, matrix.apply(&sources, true));
+    let width = pulse.width_at(self.pwm_phase);
+    let (min_width, max_width) = PULSE_WIDTH_RANGE;
+    let mut shape = Shape {
+      width,
+      partials: &settings.partials,
+      table: &settings.wavetable,
+      table_position: settings.table_position,
+    };
+    let noise = settings.noise;
+    let fm = &settings.fm;
+    let unison = settings.unison;
+    let bend = self.bend.next(&settings.bend, dt);
+    let vibrato = settings.vibrato;
+    let (mut left, mut right) = (0.0, 0.0);
+    for (
+      ((((((((voice, phases), integrators), filters), started), age), band), layer), fm_voices),
+      mod_voice,
+    ) in voices
+      .voices_mut()
+      .iter_mut()
+      .zip(self.phases.iter_mut())
+      .zip(self.integrators.iter_mut())
+      .zip(self.filters.iter_mut())
+      .zip(self.started.iter_mut())
+      .zip(self.ages.iter_mut())
+      .zip(self.bands.iter_mut())
+      .zip(self.layers.iter_mut())
+      .zip(self.fm_voices.iter_mut())
+      .zip(self.mod_voices.iter_mut())
+    {
+      let params = &layers[voice.layer];
+      let mode = params.mode;
+      let unison = unison.for_mode(mode);
+      if voice.started != *started {
+        *started = voice.started;
+        *age = 0.0;
+        if unison.random_phase && voice.state.peek(&params.envelope) == 0.0 {
+          for (phase, integrator) in phases.iter_mut().zip(integrators.iter_mut()) {
+            *phase = self.rng.unit();
+            *integrator = triangle(*phase);
+          }
+        }
+      }
+      let s = voice.state.next(&params.envelope, dt, sustain);
+      let ns = voice.noise.next(&settings.noise_envelope, dt, sustain) * noise.layer;
+      let envelopes = mod_voice.next(voice, &mod_envelopes, dt, sustain);
+      let sources = sources.voice(voice, s / params.envelope.peak(), envelopes);
+      let modulation = matrix.apply(&sources, false);
+      if s > 0.0 && voice.started > newest.0 {
+        newest = (voice.started, matrix.apply(&sources, true));
+      }
+      let cents = vibrato.cents(*age) + modulation.cents;
+      let inc = note_freq(voice.note) * pitch_ratio(bend, cents) / sample_rate;
+      *age += dt;
+      let gain = voice.gain * params.volume * modulation.gain;
+      let brightness = (voice.brightness + modulation.brightness).clamp(0.0, 1.0);
+      shape.width = (width + modulation.pulse_width).clamp(min_width, max_width);
+      if s > 0.0 {
+        for k in 0..unison.count() {
+          let (ratio, offset) = unison.copy(k);
+          let inc = inc * ratio;
+          let x = match mode {
+            NoteMode::Noise => band.next(&mut self.rng, &noise, inc, rate),
+            NoteMode::Fm => fm_voices[k].next(voice, fm, inc, brightness, dt, sustain),
+            _ => oscillate(mode, phases[k], inc, &shape, &mut integrators[k]),
+          };
+          // match the harmonic amplitudes produced by `NoteMode::calc`
+          let x = 2.0 * s * gain * unison.gain() * x;
+          // FM voices already take brightness out of their modulation
+          let v = match mode {
+            NoteMode::Fm => x,
+            _ => darken(x, brightness, inc, &mut filters[k]),
+          };
+          let (l, r) = settings.pan_gains(voice.note, offset + modulation.pan);
+          left += l * v;
+          right += r * v;
+          phases[k] = (phases[k] + inc).fract();
+        }
+      } else {
+        // so the triangle integrator starts on the waveform when the note does
+        for (phase, integrator) in phases.iter().zip(integrators.iter_mut()) {
+          *integrator = triangle(*phase);
+        }
+      }
+      if ns > 0.0 {
+        let v = 2.0 * ns * gain * layer.next(&mut self.rng, noise.color, rate);
+        let (l, r) = settings.pan_gains(voice.note, modulation.pan);
+        left += l * v;
+        right += r * v;
+      }
+    }
+    if settings.is_filtered() {
+      let filter = settings.filter_at(&newest.1);
+      let coefs = SvfCoefs::new(&filter, sample_rate);
+      let [l, r] = &mut self.lowpass;
+      (left, right) = (l.process(left, &coefs), r.process(right, &coefs));
+    }
+    let master = settings.master;
+    let limiter = settings.limiter;
+    let ([left, right], gain) = self
+      .limiter
+      .process([left * master, right * master], &limiter);
+    let control = self.audio.control();
+    control.meter(gain);
+    control.record((left + right) / 2.0);
+    self.right = Some(right);
+    left
+  }
+}
+
+/// Settings of the modes that have any, shared by every voice.
+struct Shape<'a> {
+  width: f32,
+  partials: &'a [Partial],
+  table: &'a Wavetable,
+  table_position: f32,
+}
+
+/// One sample of `mode` at phase `t` (in cycles) advancing by `dt` per sample,
+/// scaled so its fundamental has unit amplitude at a pulse width of 50%.
+fn oscillate(mode: NoteMode, t: f32, dt: f32, shape: &Shape, integrator: &mut f32) -> f32 {
+  match mode {
+    NoteMode::Sine => (TAU * t).sin(),
+    NoteMode::Saw => -PI / 2.0 * (2.0 * t - 1.0 - poly_blep(t, dt)),
+    // without its DC offset, so narrow pulses stay centred like the spectral ones
+    NoteMode::Square => {
+      let width = shape.width;
+      PI / 4.0 * (pulse(t, width, dt) - (2.0 * width - 1.0))
+    }
+    NoteMode::Triangle => {
+      // integrate a band-limited square that is a quarter cycle ahead
+      let y = *integrator * (1.0 - 0.01 * dt) + 4.0 * dt * square((t + 0.25).fract(), dt);
+      *integrator = y;
+      PI * PI / 8.0 * y
+    }
+    // rendered by `BandNoise` and `FmVoice` instead
+    NoteMode::Noise | NoteMode::Fm => 0.0,
+    // summed directly, leaving out whatever would fold back above Nyquist
+    NoteMode::Custom => (1..)
+      .zip(shape.partials)
+      .take_while(|(j, _)| *j as f32 * dt < 0.5)
+      .map(|(j, p)| p.amplitude * (TAU * j as f32 * t + p.phase).sin())
+      .sum(),
+    NoteMode::Wavetable => shape.table.sample(shape.table_position, t, dt),
+  }
+}
+
+/// One-pole lowpass that keeps roughly the same share of harmonics as the
+/// spectral engine does for a given `brightness`.
+fn darken(v: f32, brightness: f32, inc: f32, state: &mut f32) -> f32 {
+  if brightness >= 1.0 {
+    *state = v;
+    return v;
+  }
+  let harmonics = (0.5 / inc * brightness).max(1.0);
+  let cutoff = (inc * harmonics).min(0.5);
+  let a = 1.0 - (-TAU * cutoff).exp();
+  *state += a * (v - *state);
+  *state
+}
+
+/// Naive triangle in phase with a sine, used to seed the integrator.
+fn triangle(t: f32) -> f32 {
+  4.0 * ((t + 0.75).fract() - 0.5).abs() - 1.0
+}
+
+fn square(t: f32, dt: f32) -> f32 {
+  pulse(t, 0.5, dt)
+}
+
+/// Band-limited pulse that is high for the first `width` of the cycle.
+fn pulse(t: f32, width: f32, dt: f32) -> f32 {
+  let naive = if t < width { 1.0 } else { -1.0 };
+  naive + poly_blep(t, dt) - poly_blep((t + 1.0 - width).fract(), dt)
+}
+
+/// Polynomial correction for a unit step at phase zero, `dt` wide on each side.
+#[inline(always)]
+fn poly_blep(t: f32, dt: f32) -> f32 {
+  if t < dt {
+    let t = t / dt;
+    t + t - t * t - 1.0
+  } else if t > 1.0 - dt {
+    let t = (t - 1.0) / dt;
+    t * t + t + t + 1.0
+  } else {
+    0.0
+  }
+}
+
+impl Iterator for Oscillators {
+  type Item = f32;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    Some(self.calc())
+  }
+}
+impl Source for Oscillators {
+  fn current_frame_len(&self) -> Option<usize> {
+    None
+  }
+
+  fn channels(&self) -> u16 {
+    2
+  }
+
+  fn sample_rate(&self) -> u32 {
+    self.audio.control().sample_rate
+  }
+
+  fn total_duration(&self) -> Option<std::time::Duration> {
+    None
+  }
+}
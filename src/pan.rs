@@ -0,0 +1,77 @@
+use std::f32::consts::FRAC_PI_2;
+
+/// How a pan position is turned into left and right gains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanLaw {
+  /// Gains add up to one, the centre sits 6 dB down.
+  Linear,
+  /// Squared gains add up to one, the centre sits 3 dB down.
+  ConstantPower,
+  /// Halfway between the other two, 4.5 dB down in the centre.
+  Compromise,
+}
+
+impl PanLaw {
+  pub const ALL: [PanLaw; 3] = [PanLaw::Linear, PanLaw::ConstantPower, PanLaw::Compromise];
+  pub fn name(self) -> &'static str {
+    match self {
+      PanLaw::Linear => "linear",
+      PanLaw::ConstantPower => "constant-power",
+      PanLaw::Compromise => "compromise",
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|l| l.name() == name)
+  }
+  /// Left and right gains for `pan` running from -1 (hard left) to 1 (hard right).
+  pub fn gains(self, pan: f32) -> (f32, f32) {
+    let p = (pan.clamp(-1.0, 1.0) + 1.0) / 2.0;
+    match self {
+      PanLaw::Linear => (1.0 - p, p),
+      PanLaw::ConstantPower => {
+        let a = p * FRAC_PI_2;
+        (a.cos(), a.sin())
+      }
+      PanLaw::Compromise => {
+        let (ll, lr) = PanLaw::Linear.gains(pan);
+        let (pl, pr) = PanLaw::ConstantPower.gains(pan);
+        ((ll * pl).sqrt(), (lr * pr).sqrt())
+      }
+    }
+  }
+}
+
+/// Distance in semitones from `center_note` at which a full `spread` pushes a
+/// note all the way to one side.
+const SPREAD_SEMITONES: f32 = 48.0;
+
+/// Stereo placement shared by every voice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PanParams {
+  pub law: PanLaw,
+  /// Keyboard tracking: how far low notes move left and high notes move right.
+  pub spread: f32,
+  pub center_note: usize,
+}
+
+impl Default for PanParams {
+  fn default() -> Self {
+    Self {
+      law: PanLaw::ConstantPower,
+      spread: 0.5,
+      // C4
+      center_note: 48,
+    }
+  }
+}
+
+impl PanParams {
+  /// Final position of `note` whose own pan is `pan`.
+  pub fn position(&self, note: usize, pan: f32) -> f32 {
+    let track = (note as f32 - self.center_note as f32) / SPREAD_SEMITONES;
+    (pan + self.spread * track).clamp(-1.0, 1.0)
+  }
+  pub fn gains(&self, note: usize, pan: f32) -> (f32, f32) {
+    self.law.gains(self.position(note, pan))
+  }
+}
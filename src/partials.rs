@@ -0,0 +1,103 @@
+use std::path::Path;
+
+/// One harmonic of [`crate::waves::NoteMode::Custom`]; the `n`-th entry of a
+/// list is harmonic `n + 1`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Partial {
+  /// Relative to the fundamental of [`crate::waves::NoteMode::Sine`].
+  pub amplitude: f32,
+  /// Offset in radians from a sine that starts at zero.
+  pub phase: f32,
+}
+
+impl Partial {
+  pub fn new(amplitude: f32, phase: f32) -> Self {
+    Self { amplitude, phase }
+  }
+}
+
+#[derive(Debug)]
+pub enum PartialsError {
+  Io(std::io::Error),
+  /// A line that is not `amplitude [phase]`.
+  Parse {
+    line: usize,
+    text: String,
+  },
+  Empty,
+}
+
+impl std::fmt::Display for PartialsError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PartialsError::Io(e) => write!(f, "can not read partials: {e}"),
+      PartialsError::Parse { line, text } => {
+        write!(f, "line {line}: expected `amplitude [phase]`, got {text:?}")
+      }
+      PartialsError::Empty => write!(f, "no partials given"),
+    }
+  }
+}
+
+impl std::error::Error for PartialsError {}
+
+impl From<std::io::Error> for PartialsError {
+  fn from(e: std::io::Error) -> Self {
+    PartialsError::Io(e)
+  }
+}
+
+/// Reads one partial per line, the fundamental first: an amplitude optionally
+/// followed by a phase in radians. Blank lines and `#` comments are skipped.
+pub fn parse_partials(text: &str) -> Result<Vec<Partial>, PartialsError> {
+  let mut partials = vec![];
+  for (i, line) in text.lines().enumerate() {
+    let content = line.split('#').next().unwrap_or("").trim();
+    if content.is_empty() {
+      continue;
+    }
+    let error = || PartialsError::Parse {
+      line: i + 1,
+      text: line.to_string(),
+    };
+    let mut fields = content.split_whitespace().map(str::parse::<f32>);
+    let amplitude = fields.next().and_then(Result::ok).ok_or_else(error)?;
+    let phase = match fields.next() {
+      Some(phase) => phase.map_err(|_| error())?,
+      None => 0.0,
+    };
+    if fields.next().is_some() || !amplitude.is_finite() || !phase.is_finite() {
+      return Err(error());
+    }
+    partials.push(Partial::new(amplitude, phase));
+  }
+  if partials.is_empty() {
+    return Err(PartialsError::Empty);
+  }
+  Ok(partials)
+}
+
+pub fn load_partials(path: impl AsRef<Path>) -> Result<Vec<Partial>, PartialsError> {
+  parse_partials(&std::fs::read_to_string(path)?)
+}
+
+#[test]
+fn test_parse_partials() {
+  let partials = parse_partials("# drawbars\n1\n\n0.5 1.57\n0 # silent\n").unwrap();
+  assert_eq!(
+    partials,
+    [
+      Partial::new(1.0, 0.0),
+      Partial::new(0.5, 1.57),
+      Partial::new(0.0, 0.0)
+    ]
+  );
+  assert!(matches!(
+    parse_partials("1\nloud\n"),
+    Err(PartialsError::Parse { line: 2, .. })
+  ));
+  assert!(matches!(
+    parse_partials("# nothing"),
+    Err(PartialsError::Empty)
+  ));
+}
@@ -0,0 +1,97 @@
+use std::f32::consts::TAU;
+
+/// Time constant of the glide that keeps bend steps from clicking.
+const BEND_GLIDE_SECS: f32 = 0.015;
+
+/// Pitch bend shared by every voice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PitchBend {
+  /// From -1 (fully down) to 1 (fully up).
+  pub amount: f32,
+  /// Semitones a full bend moves the pitch either way.
+  pub range: f32,
+}
+
+impl Default for PitchBend {
+  fn default() -> Self {
+    Self {
+      amount: 0.0,
+      range: 2.0,
+    }
+  }
+}
+
+impl PitchBend {
+  pub fn semitones(&self) -> f32 {
+    self.amount.clamp(-1.0, 1.0) * self.range
+  }
+}
+
+/// Periodic pitch wobble every note picks up after a delay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VibratoParams {
+  /// Speed in Hz.
+  pub rate: f32,
+  /// Swing to either side in cents; 0 turns it off.
+  pub depth: f32,
+  /// Seconds into a note before the vibrato starts to fade in.
+  pub delay: f32,
+}
+
+impl Default for VibratoParams {
+  fn default() -> Self {
+    Self {
+      rate: 5.5,
+      depth: 0.0,
+      delay: 0.3,
+    }
+  }
+}
+
+impl VibratoParams {
+  /// Offset in cents `age` seconds into a note. The vibrato fades in over its
+  /// first cycle, so it never starts with a jump.
+  pub fn cents(&self, age: f32) -> f32 {
+    let t = age - self.delay;
+    if self.depth == 0.0 || t <= 0.0 {
+      return 0.0;
+    }
+    let fade = (t * self.rate).min(1.0);
+    self.depth * fade * (TAU * self.rate * t).sin()
+  }
+}
+
+/// Follows a bend towards where it was last set, so the pitch slides
+/// rather than jumps when the wheel moves in steps.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BendGlide {
+  semitones: f32,
+}
+
+impl BendGlide {
+  /// Semitones after moving `dt` seconds towards `bend`.
+  pub fn next(&mut self, bend: &PitchBend, dt: f32) -> f32 {
+    let a = 1.0 - (-dt / BEND_GLIDE_SECS).exp();
+    self.semitones += a * (bend.semitones() - self.semitones);
+    self.semitones
+  }
+}
+
+/// Frequency multiple of a shift by `semitones` and `cents`.
+pub fn pitch_ratio(semitones: f32, cents: f32) -> f32 {
+  2f32.powf(semitones / 12.0 + cents / 1200.0)
+}
+
+#[test]
+fn test_vibrato() {
+  let vibrato = VibratoParams {
+    rate: 5.0,
+    depth: 50.0,
+    delay: 0.2,
+  };
+  assert_eq!(vibrato.cents(0.1), 0.0);
+  // a quarter cycle in, while still fading in
+  assert!((vibrato.cents(0.25) - 12.5).abs() < 1e-3);
+  assert!((vibrato.cents(1.45) - 50.0).abs() < 1e-3);
+  assert!((pitch_ratio(12.0, -1200.0) - 1.0).abs() < 1e-6);
+}
@@ -0,0 +1,486 @@
+use crate::envelope::EnvelopeParams;
+use crate::filter::FilterParams;
+use crate::fm::{FmAlgorithm, FmParams, Operator};
+use crate::lfo::{LfoParams, LfoRate, LfoShape, LFO_COUNT};
+use crate::limiter::LimiterParams;
+use crate::modulation::{ModDestination, ModMatrix, ModSource, Route, MOD_ENVELOPES};
+use crate::noise::{NoiseColor, NoiseParams};
+use crate::pan::{PanLaw, PanParams};
+use crate::partials::Partial;
+use crate::pitch::VibratoParams;
+use crate::voices::{
+  default_layers, LayerParams, UnisonParams, VelocityCurve, VelocityParams, MAX_LAYERS,
+};
+use crate::waves::{NoteMode, PulseParams, WavesControl};
+use std::{fmt::Write, path::Path};
+
+#[derive(Debug)]
+pub enum PresetError {
+  Io(std::io::Error),
+  /// A line that does not read as a setting.
+  Parse {
+    line: usize,
+    text: String,
+  },
+}
+
+impl std::fmt::Display for PresetError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PresetError::Io(e) => write!(f, "can not access preset: {e}"),
+      PresetError::Parse { line, text } => write!(f, "line {line}: unexpected {text:?}"),
+    }
+  }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<std::io::Error> for PresetError {
+  fn from(e: std::io::Error) -> Self {
+    PresetError::Io(e)
+  }
+}
+
+/// The settings of a sound, saved as one `name values...` line each. A
+/// wavetable is loaded from its own file and is not saved, only the position
+/// in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+  pub layers: [LayerParams; MAX_LAYERS],
+  pub pulse: PulseParams,
+  pub unison: UnisonParams,
+  pub pan: PanParams,
+  pub velocity: VelocityParams,
+  pub bend_range: f32,
+  pub vibrato: VibratoParams,
+  pub tempo: f32,
+  pub filter: FilterParams,
+  pub master: f32,
+  pub limiter: LimiterParams,
+  pub lfos: [LfoParams; LFO_COUNT],
+  pub matrix: ModMatrix,
+  pub mod_envelopes: [EnvelopeParams; MOD_ENVELOPES],
+  pub noise_envelope: EnvelopeParams,
+  pub noise: NoiseParams,
+  pub partials: Vec<Partial>,
+  pub fm: FmParams,
+  pub table_position: f32,
+}
+
+impl Default for Preset {
+  fn default() -> Self {
+    Self {
+      layers: default_layers(),
+      pulse: PulseParams::default(),
+      unison: UnisonParams::default(),
+      pan: PanParams::default(),
+      velocity: VelocityParams::default(),
+      bend_range: 2.0,
+      vibrato: VibratoParams::default(),
+      tempo: 120.0,
+      filter: FilterParams::default(),
+      master: 1.0,
+      limiter: LimiterParams::default(),
+      lfos: [LfoParams::default(); LFO_COUNT],
+      matrix: ModMatrix::default(),
+      mod_envelopes: [EnvelopeParams::default(); MOD_ENVELOPES],
+      noise_envelope: EnvelopeParams::burst(0.01, 0.15),
+      noise: NoiseParams::default(),
+      partials: vec![Partial::new(1.0, 0.0)],
+      fm: FmParams::default(),
+      table_position: 0.0,
+    }
+  }
+}
+
+impl Preset {
+  /// What `control` is set to now.
+  pub fn capture(control: &WavesControl) -> Self {
+    Self {
+      layers: control.layers(),
+      pulse: control.pulse(),
+      unison: control.unison(),
+      pan: control.panning(),
+      velocity: control.velocity(),
+      bend_range: control.bend().range,
+      vibrato: control.vibrato(),
+      tempo: control.tempo(),
+      filter: control.filter(),
+      master: control.master(),
+      limiter: control.limiter(),
+      lfos: control.lfos(),
+      matrix: ModMatrix::clone(&control.mod_matrix()),
+      mod_envelopes: control.mod_envelopes(),
+      noise_envelope: control.noise_envelope(),
+      noise: control.noise(),
+      partials: control.partials().to_vec(),
+      fm: control.fm(),
+      table_position: control.table_position(),
+    }
+  }
+  pub fn apply(&self, control: &WavesControl) {
+    for (i, layer) in self.layers.iter().enumerate() {
+      control.set_layer(i, *layer);
+    }
+    control.set_pulse(self.pulse);
+    control.set_unison(self.unison);
+    control.set_panning(self.pan);
+    control.set_velocity(self.velocity);
+    control.set_bend_range(self.bend_range);
+    control.set_vibrato(self.vibrato);
+    control.set_tempo(self.tempo);
+    control.set_filter(self.filter);
+    control.set_master(self.master);
+    control.set_limiter(self.limiter);
+    for (i, lfo) in self.lfos.iter().enumerate() {
+      control.set_lfo(i, *lfo);
+    }
+    control.set_mod_matrix(self.matrix.clone());
+    for (i, envelope) in self.mod_envelopes.iter().enumerate() {
+      control.set_mod_envelope(i, *envelope);
+    }
+    control.set_noise_envelope(self.noise_envelope);
+    control.set_noise(self.noise);
+    control.set_partials(self.partials.as_slice());
+    control.set_fm(self.fm);
+    control.set_table_position(self.table_position);
+  }
+  /// Reads the lines written by [`Preset::to_text`]. Settings left out keep
+  /// their defaults; blank lines and `#` comments are skipped.
+  pub fn parse(text: &str) -> Result<Self, PresetError> {
+    let mut preset = Preset::default();
+    for (i, line) in text.lines().enumerate() {
+      let content = line.split('#').next().unwrap_or("").trim();
+      if content.is_empty() {
+        continue;
+      }
+      let fields: Vec<&str> = content.split_whitespace().collect();
+      preset.set(&fields).ok_or_else(|| PresetError::Parse {
+        line: i + 1,
+        text: line.to_string(),
+      })?;
+    }
+    Ok(preset)
+  }
+  /// Applies one line, or returns `None` if it does not read.
+  fn set(&mut self, fields: &[&str]) -> Option<()> {
+    let num = |i: usize| fields.get(i)?.parse::<f32>().ok();
+    match fields {
+      ["layer", index, state, mode, _, keys] => {
+        let index: usize = index.parse().ok()?;
+        let layer = self.layers.get_mut(index.checked_sub(1)?)?;
+        let (lowest, highest) = keys.split_once('-')?;
+        *layer = LayerParams {
+          enabled: match *state {
+            "on" => true,
+            "off" => false,
+            _ => return None,
+          },
+          mode: NoteMode::from_name(mode)?,
+          volume: num(4)?,
+          keys: (
+            lowest.parse().ok()?,
+            match highest {
+              "" => usize::MAX,
+              highest => highest.parse().ok()?,
+            },
+          ),
+          ..*layer
+        };
+      }
+      [kind @ ("envelope" | "mod-envelope"), index, spec] => {
+        let index = index.parse::<usize>().ok()?.checked_sub(1)?;
+        let envelope = if *kind == "envelope" {
+          &mut self.layers.get_mut(index)?.envelope
+        } else {
+          self.mod_envelopes.get_mut(index)?
+        };
+        *envelope = EnvelopeParams::from_name(spec)?;
+      }
+      ["noise-envelope", spec] => self.noise_envelope = EnvelopeParams::from_name(spec)?,
+      ["noise", color, _, _] => {
+        self.noise = NoiseParams {
+          color: NoiseColor::from_name(color)?,
+          bandwidth: num(2)?,
+          layer: num(3)?,
+        }
+      }
+      // `amplitude/phase` of every partial, separated by commas
+      ["partials", list] => {
+        let partial = |p: &str| {
+          let (amplitude, phase) = p.split_once('/')?;
+          Some(Partial::new(amplitude.parse().ok()?, phase.parse().ok()?))
+        };
+        self.partials = list.split(',').map(partial).collect::<Option<_>>()?;
+      }
+      ["fm", algorithm, count, _] => {
+        self.fm.algorithm = FmAlgorithm::from_name(algorithm)?;
+        self.fm.count = count.parse().ok()?;
+        self.fm.feedback = num(3)?;
+      }
+      ["operator", index, _, _, _, envelope] => {
+        let index: usize = index.parse().ok()?;
+        *self.fm.operators.get_mut(index.checked_sub(1)?)? = Operator {
+          ratio: num(2)?,
+          detune: num(3)?,
+          level: num(4)?,
+          envelope: match *envelope {
+            "none" => None,
+            spec => Some(EnvelopeParams::from_name(spec)?),
+          },
+        };
+      }
+      ["table-position", _] => self.table_position = num(1)?,
+      ["pulse", ..] if fields.len() == 4 => {
+        self.pulse = PulseParams {
+          width: num(1)?,
+          pwm_depth: num(2)?,
+          pwm_rate: num(3)?,
+        }
+      }
+      ["unison", voices, _, _, phase] => {
+        self.unison = UnisonParams {
+          voices: voices.parse().ok()?,
+          detune: num(2)?,
+          spread: num(3)?,
+          random_phase: match *phase {
+            "random" => true,
+            "fixed" => false,
+            _ => return None,
+          },
+        }
+      }
+      ["pan", law, _, center] => {
+        self.pan = PanParams {
+          law: PanLaw::from_name(law)?,
+          spread: num(2)?,
+          center_note: center.parse().ok()?,
+        }
+      }
+      ["velocity", curve, _] => {
+        self.velocity = VelocityParams {
+          curve: VelocityCurve::from_name(curve)?,
+          brightness: num(2)?,
+        }
+      }
+      ["bend-range", _] => self.bend_range = num(1)?,
+      ["vibrato", ..] if fields.len() == 4 => {
+        self.vibrato = VibratoParams {
+          rate: num(1)?,
+          depth: num(2)?,
+          delay: num(3)?,
+        }
+      }
+      ["tempo", _] => self.tempo = num(1)?,
+      ["filter", _, _] => {
+        self.filter = FilterParams {
+          cutoff: num(1)?,
+          resonance: num(2)?,
+        }
+      }
+      ["master", _] => self.master = num(1)?,
+      ["limiter", _, _] => {
+        self.limiter = LimiterParams {
+          ceiling: num(1)?,
+          release: num(2)?,
+        }
+      }
+      ["lfo", index, shape, rate, _, retrigger] => {
+        let index: usize = index.parse().ok()?;
+        *self.lfos.get_mut(index.checked_sub(1)?)? = LfoParams {
+          shape: LfoShape::from_name(shape)?,
+          rate: LfoRate::from_name(rate)?,
+          phase: num(4)?,
+          retrigger: match *retrigger {
+            "retrigger" => true,
+            "free" => false,
+            _ => return None,
+          },
+        };
+      }
+      ["route", source, destination, _] => self.matrix.routes.push(Route::new(
+        ModSource::from_name(source)?,
+        ModDestination::from_name(destination)?,
+        num(3)?,
+      )),
+      _ => return None,
+    }
+    Some(())
+  }
+  pub fn to_text(&self) -> String {
+    let mut text = String::new();
+    let p = &self.pulse;
+    let u = &self.unison;
+    let v = &self.vibrato;
+    let phase = if u.random_phase { "random" } else { "fixed" };
+    // writing to a `String` can not fail
+    for (i, layer) in self.layers.iter().enumerate() {
+      let state = if layer.enabled { "on" } else { "off" };
+      let (mode, volume) = (layer.mode.name(), layer.volume);
+      // a range open at the top ends in a bare `-`
+      let (lowest, highest) = layer.keys;
+      let highest = if highest == usize::MAX {
+        String::new()
+      } else {
+        highest.to_string()
+      };
+      let _ = writeln!(
+        text,
+        "layer {} {state} {mode} {volume} {lowest}-{highest}",
+        i + 1
+      );
+      let _ = writeln!(text, "envelope {} {}", i + 1, layer.envelope.name());
+    }
+    for (i, envelope) in self.mod_envelopes.iter().enumerate() {
+      let _ = writeln!(text, "mod-envelope {} {}", i + 1, envelope.name());
+    }
+    let _ = writeln!(text, "noise-envelope {}", self.noise_envelope.name());
+    let n = &self.noise;
+    let color = n.color.name();
+    let _ = writeln!(text, "noise {color} {} {}", n.bandwidth, n.layer);
+    let partials: Vec<String> = self
+      .partials
+      .iter()
+      .map(|p| format!("{}/{}", p.amplitude, p.phase))
+      .collect();
+    if !partials.is_empty() {
+      let _ = writeln!(text, "partials {}", partials.join(","));
+    }
+    let fm = &self.fm;
+    let algorithm = fm.algorithm.name();
+    let _ = writeln!(text, "fm {algorithm} {} {}", fm.count, fm.feedback);
+    for (i, op) in fm.operators.iter().enumerate() {
+      let envelope = op.envelope.map_or("none".into(), |e| e.name());
+      let _ = writeln!(
+        text,
+        "operator {} {} {} {} {envelope}",
+        i + 1,
+        op.ratio,
+        op.detune,
+        op.level
+      );
+    }
+    let _ = writeln!(text, "table-position {}", self.table_position);
+    let _ = writeln!(text, "pulse {} {} {}", p.width, p.pwm_depth, p.pwm_rate);
+    let _ = writeln!(
+      text,
+      "unison {} {} {} {phase}",
+      u.voices, u.detune, u.spread
+    );
+    let pan = &self.pan;
+    let _ = writeln!(
+      text,
+      "pan {} {} {}",
+      pan.law.name(),
+      pan.spread,
+      pan.center_note
+    );
+    let velocity = &self.velocity;
+    let curve = velocity.curve.name();
+    let _ = writeln!(text, "velocity {curve} {}", velocity.brightness);
+    let _ = writeln!(text, "bend-range {}", self.bend_range);
+    let _ = writeln!(text, "vibrato {} {} {}", v.rate, v.depth, v.delay);
+    let _ = writeln!(text, "tempo {}", self.tempo);
+    let _ = writeln!(
+      text,
+      "filter {} {}",
+      self.filter.cutoff, self.filter.resonance
+    );
+    let _ = writeln!(text, "master {}", self.master);
+    let l = &self.limiter;
+    let _ = writeln!(text, "limiter {} {}", l.ceiling, l.release);
+    for (i, lfo) in self.lfos.iter().enumerate() {
+      let retrigger = if lfo.retrigger { "retrigger" } else { "free" };
+      let (shape, rate) = (lfo.shape.name(), lfo.rate.name());
+      let _ = writeln!(
+        text,
+        "lfo {} {shape} {rate} {} {retrigger}",
+        i + 1,
+        lfo.phase
+      );
+    }
+    for route in &self.matrix.routes {
+      let (source, destination) = (route.source.name(), route.destination.name());
+      let _ = writeln!(text, "route {source} {destination} {}", route.depth);
+    }
+    text
+  }
+  pub fn load(path: impl AsRef<Path>) -> Result<Self, PresetError> {
+    Self::parse(&std::fs::read_to_string(path)?)
+  }
+  pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PresetError> {
+    Ok(std::fs::write(path, self.to_text())?)
+  }
+}
+
+#[test]
+fn test_preset_text() {
+  use crate::envelope::Breakpoint;
+  use crate::lerp::Curve;
+  let mut preset = Preset {
+    tempo: 96.5,
+    master: 0.75,
+    pan: PanParams {
+      law: PanLaw::Compromise,
+      spread: 0.25,
+      center_note: 40,
+    },
+    velocity: VelocityParams {
+      curve: VelocityCurve::Fixed(0.8),
+      brightness: 0.5,
+    },
+    ..Preset::default()
+  };
+  preset.layers[0].keys = (0, 47);
+  preset.layers[1] = LayerParams {
+    enabled: true,
+    mode: NoteMode::Square,
+    volume: 0.5,
+    keys: (48, usize::MAX),
+    envelope: EnvelopeParams::adsr(0.8, 0.005, 0.3, 0.5, 1.2)
+      .unwrap()
+      .with_curves(&[Curve::SCurve, Curve::Linear, Curve::Bezier(0.2, 0.9)]),
+  };
+  let points = [
+    Breakpoint::new(0.0, 0.5),
+    Breakpoint::new(1.0, 0.25),
+    Breakpoint::new(0.5, 0.25),
+    Breakpoint::new(0.0, 1.0),
+  ];
+  preset.mod_envelopes[1] = EnvelopeParams::new(&points, Some(2), Some((1, 2))).unwrap();
+  preset.noise_envelope = EnvelopeParams::burst(0.002, 0.5);
+  preset.noise = NoiseParams {
+    color: NoiseColor::Brown,
+    bandwidth: 0.5,
+    layer: 0.25,
+  };
+  preset.partials = vec![Partial::new(1.0, 0.0), Partial::new(0.5, 1.5)];
+  preset.fm.algorithm = FmAlgorithm::TwoStacks;
+  preset.fm.count = 4;
+  preset.fm.operators[2] = Operator {
+    detune: -7.0,
+    envelope: Some(EnvelopeParams::burst(0.01, 0.2)),
+    ..Operator::new(3.5, 0.8)
+  };
+  preset.table_position = 0.75;
+  preset.lfos[1] = LfoParams {
+    shape: LfoShape::SampleHold,
+    rate: LfoRate::Beats(0.25),
+    phase: 0.5,
+    retrigger: true,
+  };
+  preset.matrix.routes = vec![
+    Route::new(ModSource::Lfo(1), ModDestination::Cutoff, 1.5),
+    Route::new(ModSource::Cc(74), ModDestination::Brightness, -0.3),
+  ];
+  assert_eq!(Preset::parse(&preset.to_text()).unwrap(), preset);
+  let short = Preset::parse("envelope 1 1/0.1,0.5/0.2,0/0.3,sustain=2\n").unwrap();
+  assert_eq!(
+    short.layers[0].envelope,
+    EnvelopeParams::adsr(1.0, 0.1, 0.2, 0.5, 0.3).unwrap()
+  );
+  assert!(matches!(
+    Preset::parse("tempo 120\nroute lfo1 nowhere 1\n"),
+    Err(PresetError::Parse { line: 2, .. })
+  ));
+}
@@ -0,0 +1,114 @@
+use std::{
+  cell::UnsafeCell,
+  mem::MaybeUninit,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
+
+/// Fixed ring of slots shared by one [`Producer`] and one [`Consumer`].
+struct Ring<T> {
+  slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+  /// Items ever pushed, only stored to by the producer.
+  head: AtomicUsize,
+  /// Items ever popped, only stored to by the consumer.
+  tail: AtomicUsize,
+}
+
+// a slot belongs to the producer until `head` moves past it and to the
+// consumer until `tail` does, so the two ends never touch the same one
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Drop for Ring<T> {
+  fn drop(&mut self) {
+    let (head, mut tail) = (*self.head.get_mut(), *self.tail.get_mut());
+    while tail != head {
+      let slot = &mut self.slots[tail % self.slots.len()];
+      unsafe { slot.get_mut().assume_init_drop() };
+      tail = tail.wrapping_add(1);
+    }
+  }
+}
+
+/// Sending end of a queue made by [`queue`].
+pub struct Producer<T>(Arc<Ring<T>>);
+
+/// Receiving end of a queue made by [`queue`].
+pub struct Consumer<T>(Arc<Ring<T>>);
+
+/// Single-producer single-consumer queue holding up to `capacity` items.
+/// Neither end ever blocks, so the receiving one can live on the audio thread.
+pub fn queue<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+  assert!(capacity > 0, "queue capacity must be positive");
+  let ring = Arc::new(Ring {
+    slots: (0..capacity)
+      .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+      .collect(),
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+  });
+  (Producer(Arc::clone(&ring)), Consumer(ring))
+}
+
+impl<T> Producer<T> {
+  /// Adds `item` at the back, or hands it back if the queue is full.
+  pub fn push(&mut self, item: T) -> Result<(), T> {
+    let ring = &*self.0;
+    let head = ring.head.load(Ordering::Relaxed);
+    if head.wrapping_sub(ring.tail.load(Ordering::Acquire)) == ring.slots.len() {
+      return Err(item);
+    }
+    let slot = &ring.slots[head % ring.slots.len()];
+    unsafe { (*slot.get()).write(item) };
+    ring.head.store(head.wrapping_add(1), Ordering::Release);
+    Ok(())
+  }
+}
+
+impl<T> Consumer<T> {
+  /// Takes the item at the front, if any.
+  pub fn pop(&mut self) -> Option<T> {
+    let ring = &*self.0;
+    let tail = ring.tail.load(Ordering::Relaxed);
+    if tail == ring.head.load(Ordering::Acquire) {
+      return None;
+    }
+    let slot = &ring.slots[tail % ring.slots.len()];
+    let item = unsafe { (*slot.get()).assume_init_read() };
+    ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+    Some(item)
+  }
+}
+
+#[test]
+fn test_queue() {
+  let (mut producer, mut consumer) = queue(4);
+  for i in 0..4 {
+    assert!(producer.push(Arc::new(i)).is_ok());
+  }
+  assert!(producer.push(Arc::new(4)).is_err());
+  assert_eq!(consumer.pop().as_deref(), Some(&0));
+  // items come out in order across threads
+  let sender = std::thread::spawn(move || {
+    for i in 4..1000 {
+      let mut item = Arc::new(i);
+      while let Err(back) = producer.push(item) {
+        item = back;
+        std::thread::yield_now();
+      }
+    }
+  });
+  // stop once the last few fit, leaving them to be dropped with the queue
+  let mut expected = 1;
+  while expected < 1000 - 4 {
+    match consumer.pop() {
+      Some(i) => {
+        assert_eq!(*i, expected);
+        expected += 1;
+      }
+      None => std::thread::yield_now(),
+    }
+  }
+  sender.join().unwrap();
+}
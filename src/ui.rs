@@ -247,6 +247,212 @@ impl<Coord, DB: DrawingBackend> Drawable<DB> for SquareIcon<Coord> {
   }
 }
 
+pub struct CustomIcon<Coord> {
+  pub pos: [Coord; 1],
+  pub size: i32,
+  pub style: ShapeStyle,
+}
+impl<Coord> CustomIcon<Coord> {
+  pub fn new(pos: Coord, size: u32, style: impl Into<ShapeStyle>) -> Self {
+    Self {
+      pos: [pos],
+      size: size as i32,
+      style: style.into(),
+    }
+  }
+}
+
+impl<'a, Coord> PointCollection<'a, Coord> for &'a CustomIcon<Coord> {
+  type Point = &'a Coord;
+  type IntoIter = &'a [Coord];
+  fn point_iter(self) -> &'a [Coord] {
+    &self.pos
+  }
+}
+
+impl<Coord, DB: DrawingBackend> Drawable<DB> for CustomIcon<Coord> {
+  fn draw<I: Iterator<Item = BackendCoord>>(
+    &self,
+    mut points: I,
+    backend: &mut DB,
+    pd: (u32, u32),
+  ) -> Result<(), DrawingErrorKind<DB::ErrorType>> {
+    let Some(lt) = points.next() else {
+      return Ok(());
+    };
+    let bbox = RoundedRect::new([lt, (lt.0 + self.size, lt.1 + self.size)], 5, self.style);
+    bbox.draw(bbox.point_iter().iter().copied(), backend, pd)?;
+    // a few harmonic bars of uneven height
+    for (i, h) in [8, 3, 6, 2].into_iter().enumerate() {
+      let x = lt.0 + (2 * i as i32 + 2) * self.size / 10;
+      backend.draw_line(
+        (x, lt.1 + 9 * self.size / 10),
+        (x, lt.1 + (9 - h) * self.size / 10),
+        &self.style,
+      )?;
+    }
+    Ok(())
+  }
+}
+
+pub struct NoiseIcon<Coord> {
+  pub pos: [Coord; 1],
+  pub size: i32,
+  pub style: ShapeStyle,
+}
+impl<Coord> NoiseIcon<Coord> {
+  pub fn new(pos: Coord, size: u32, style: impl Into<ShapeStyle>) -> Self {
+    Self {
+      pos: [pos],
+      size: size as i32,
+      style: style.into(),
+    }
+  }
+}
+
+impl<'a, Coord> PointCollection<'a, Coord> for &'a NoiseIcon<Coord> {
+  type Point = &'a Coord;
+  type IntoIter = &'a [Coord];
+  fn point_iter(self) -> &'a [Coord] {
+    &self.pos
+  }
+}
+
+impl<Coord, DB: DrawingBackend> Drawable<DB> for NoiseIcon<Coord> {
+  fn draw<I: Iterator<Item = BackendCoord>>(
+    &self,
+    mut points: I,
+    backend: &mut DB,
+    pd: (u32, u32),
+  ) -> Result<(), DrawingErrorKind<DB::ErrorType>> {
+    let Some(lt) = points.next() else {
+      return Ok(());
+    };
+    let bbox = RoundedRect::new([lt, (lt.0 + self.size, lt.1 + self.size)], 5, self.style);
+    bbox.draw(bbox.point_iter().iter().copied(), backend, pd)?;
+    // a fixed jagged line, so the icon does not flicker
+    let heights = [5, 2, 7, 3, 8, 1, 6, 4, 5];
+    for (i, pair) in heights.windows(2).enumerate() {
+      let x = lt.0 + self.size / 10 + i as i32 * self.size / 10;
+      backend.draw_line(
+        (x, lt.1 + (1 + pair[0]) * self.size / 10),
+        (x + self.size / 10, lt.1 + (1 + pair[1]) * self.size / 10),
+        &self.style,
+      )?;
+    }
+    Ok(())
+  }
+}
+
+pub struct FmIcon<Coord> {
+  pub pos: [Coord; 1],
+  pub size: i32,
+  pub style: ShapeStyle,
+}
+impl<Coord> FmIcon<Coord> {
+  pub fn new(pos: Coord, size: u32, style: impl Into<ShapeStyle>) -> Self {
+    Self {
+      pos: [pos],
+      size: size as i32,
+      style: style.into(),
+    }
+  }
+}
+
+impl<'a, Coord> PointCollection<'a, Coord> for &'a FmIcon<Coord> {
+  type Point = &'a Coord;
+  type IntoIter = &'a [Coord];
+  fn point_iter(self) -> &'a [Coord] {
+    &self.pos
+  }
+}
+
+impl<Coord, DB: DrawingBackend> Drawable<DB> for FmIcon<Coord> {
+  fn draw<I: Iterator<Item = BackendCoord>>(
+    &self,
+    mut points: I,
+    backend: &mut DB,
+    pd: (u32, u32),
+  ) -> Result<(), DrawingErrorKind<DB::ErrorType>> {
+    let Some(lt) = points.next() else {
+      return Ok(());
+    };
+    let bbox = RoundedRect::new([lt, (lt.0 + self.size, lt.1 + self.size)], 5, self.style);
+    bbox.draw(bbox.point_iter().iter().copied(), backend, pd)?;
+    // a sine whose phase is pushed around by a second sine
+    let segments = 100;
+    let mut prev = 0;
+    for i in 0..segments {
+      let x = i as f32 / segments as f32 * 2.0 * std::f32::consts::PI;
+      let h = (((x + 2.0 * (2.0 * x).sin()).sin() + 1.0) * self.size as f32 * 0.4) as i32;
+      if i != 0 {
+        backend.draw_line(
+          (
+            i * 8 * self.size / 10 / segments + lt.0 + self.size / 10,
+            prev + lt.1 + self.size / 10,
+          ),
+          (
+            (i + 1) * 8 * self.size / 10 / segments + lt.0 + self.size / 10,
+            h + lt.1 + self.size / 10,
+          ),
+          &self.style,
+        )?;
+      }
+      prev = h;
+    }
+    Ok(())
+  }
+}
+
+pub struct WavetableIcon<Coord> {
+  pub pos: [Coord; 1],
+  pub size: i32,
+  pub style: ShapeStyle,
+}
+impl<Coord> WavetableIcon<Coord> {
+  pub fn new(pos: Coord, size: u32, style: impl Into<ShapeStyle>) -> Self {
+    Self {
+      pos: [pos],
+      size: size as i32,
+      style: style.into(),
+    }
+  }
+}
+
+impl<'a, Coord> PointCollection<'a, Coord> for &'a WavetableIcon<Coord> {
+  type Point = &'a Coord;
+  type IntoIter = &'a [Coord];
+  fn point_iter(self) -> &'a [Coord] {
+    &self.pos
+  }
+}
+
+impl<Coord, DB: DrawingBackend> Drawable<DB> for WavetableIcon<Coord> {
+  fn draw<I: Iterator<Item = BackendCoord>>(
+    &self,
+    mut points: I,
+    backend: &mut DB,
+    pd: (u32, u32),
+  ) -> Result<(), DrawingErrorKind<DB::ErrorType>> {
+    let Some(lt) = points.next() else {
+      return Ok(());
+    };
+    let bbox = RoundedRect::new([lt, (lt.0 + self.size, lt.1 + self.size)], 5, self.style);
+    bbox.draw(bbox.point_iter().iter().copied(), backend, pd)?;
+    // three frames stacked back to front, each one step further along
+    for frame in 0..3 {
+      let x0 = lt.0 + (1 + 2 * frame) * self.size / 10;
+      let y0 = lt.1 + (7 - 2 * frame) * self.size / 10;
+      let w = 4 * self.size / 10;
+      let h = 2 * self.size / 10;
+      backend.draw_line((x0, y0), (x0 + w / 2, y0 - h), &self.style)?;
+      backend.draw_line((x0 + w / 2, y0 - h), (x0 + w / 2, y0 + h / 2), &self.style)?;
+      backend.draw_line((x0 + w / 2, y0 + h / 2), (x0 + w, y0), &self.style)?;
+    }
+    Ok(())
+  }
+}
+
 pub struct SineIcon<Coord> {
   pub pos: [Coord; 1],
   pub size: i32,
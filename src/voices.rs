@@ -0,0 +1,437 @@
+use crate::envelope::{EnvelopeParams, NoteState};
+use crate::waves::NoteMode;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Voice {
+  pub note: usize,
+  pub state: NoteState,
+  /// Envelope of the noise layer, started and released with `state`.
+  pub noise: NoteState,
+  /// Allocation counter value at the time the voice was started.
+  pub started: u64,
+  /// Note-off arrived while the sustain pedal was down.
+  pub held: bool,
+  /// Amplitude scale from the note's velocity.
+  pub gain: f32,
+  /// Fraction of the available harmonics the note is rendered with.
+  pub brightness: f32,
+  /// As played, from 0 to 1, for the modulation matrix.
+  pub velocity: f32,
+  /// Index of the [`LayerParams`] the note is played with.
+  pub layer: usize,
+}
+
+impl Voice {
+  pub fn is_active(&self) -> bool {
+    !matches!(self.state, NoteState::Silent)
+  }
+}
+
+/// Maps a note's velocity, from 0 to 1, to its amplitude.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VelocityCurve {
+  Linear,
+  /// Velocity raised to the given power; above 1 soft notes get softer.
+  Exponential(f32),
+  /// Every note plays at the same level, whatever its velocity.
+  Fixed(f32),
+}
+
+impl VelocityCurve {
+  /// `exponential:<power>` or `fixed:<level>`, or `linear`.
+  pub fn name(self) -> String {
+    match self {
+      VelocityCurve::Linear => "linear".into(),
+      VelocityCurve::Exponential(power) => format!("exponential:{power}"),
+      VelocityCurve::Fixed(level) => format!("fixed:{level}"),
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name.split_once(':') {
+      None if name == "linear" => Some(VelocityCurve::Linear),
+      Some(("exponential", power)) => Some(VelocityCurve::Exponential(power.parse().ok()?)),
+      Some(("fixed", level)) => Some(VelocityCurve::Fixed(level.parse().ok()?)),
+      _ => None,
+    }
+  }
+  pub fn apply(self, velocity: f32) -> f32 {
+    let velocity = velocity.clamp(0.0, 1.0);
+    match self {
+      VelocityCurve::Linear => velocity,
+      VelocityCurve::Exponential(power) => velocity.powf(power),
+      VelocityCurve::Fixed(level) => level,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VelocityParams {
+  pub curve: VelocityCurve,
+  /// How much soft notes lose their upper harmonics, from 0 (not at all) to 1.
+  pub brightness: f32,
+}
+
+impl Default for VelocityParams {
+  fn default() -> Self {
+    Self {
+      curve: VelocityCurve::Exponential(2.0),
+      brightness: 0.0,
+    }
+  }
+}
+
+impl VelocityParams {
+  /// Gain and brightness of a note played at `velocity`.
+  pub fn apply(&self, velocity: f32) -> (f32, f32) {
+    let gain = self.curve.apply(velocity);
+    (gain, 1.0 - self.brightness * (1.0 - gain))
+  }
+}
+
+/// Most copies a unison stack can have.
+pub const MAX_UNISON: usize = 16;
+
+/// Detuned copies every note is played with. The whole stack takes one voice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnisonParams {
+  /// Copies per note, from 1 to [`MAX_UNISON`].
+  pub voices: usize,
+  /// Distance in cents between the lowest and the highest copy.
+  pub detune: f32,
+  /// How far apart the copies are panned, from 0 (all together) to 1.
+  pub spread: f32,
+  /// Start every copy at a random phase when the note starts from silence;
+  /// a retriggered or stolen voice keeps its phases, as jumping them would
+  /// click.
+  pub random_phase: bool,
+}
+
+impl Default for UnisonParams {
+  fn default() -> Self {
+    Self {
+      voices: 1,
+      detune: 20.0,
+      spread: 0.5,
+      random_phase: true,
+    }
+  }
+}
+
+impl UnisonParams {
+  pub fn count(&self) -> usize {
+    self.voices.clamp(1, MAX_UNISON)
+  }
+  /// Frequency multiple and pan offset of copy `k`, spread evenly around the
+  /// note from the lowest and leftmost copy to the highest and rightmost.
+  pub fn copy(&self, k: usize) -> (f32, f32) {
+    let n = self.count();
+    if n == 1 {
+      return (1.0, 0.0);
+    }
+    let x = 2.0 * k as f32 / (n - 1) as f32 - 1.0;
+    (2f32.powf(x * self.detune / 2400.0), x * self.spread)
+  }
+  /// Gain of every copy, so that the stack is about as loud as a single one.
+  pub fn gain(&self) -> f32 {
+    1.0 / (self.count() as f32).sqrt()
+  }
+  /// The stack notes of `mode` are played with. Noise is random already, so
+  /// detuned copies of it would only add up.
+  pub fn for_mode(self, mode: NoteMode) -> Self {
+    match mode {
+      NoteMode::Noise => Self { voices: 1, ..self },
+      _ => self,
+    }
+  }
+}
+
+/// Most layers a key can play at once.
+pub const MAX_LAYERS: usize = 4;
+
+/// One timbre of the keyboard. Layers whose key ranges overlap sound
+/// together; ranges side by side split the keyboard between them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerParams {
+  pub enabled: bool,
+  pub mode: NoteMode,
+  pub envelope: EnvelopeParams,
+  pub volume: f32,
+  /// Lowest and highest note played, counting semitones up from C0.
+  pub keys: (usize, usize),
+}
+
+impl Default for LayerParams {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      mode: NoteMode::Sine,
+      envelope: EnvelopeParams::default(),
+      volume: 1.0,
+      keys: (0, usize::MAX),
+    }
+  }
+}
+
+/// Only the first layer playing, across the whole keyboard.
+pub fn default_layers() -> [LayerParams; MAX_LAYERS] {
+  std::array::from_fn(|i| LayerParams {
+    enabled: i == 0,
+    ..LayerParams::default()
+  })
+}
+
+impl LayerParams {
+  /// Whether pressing `note` starts a voice of this layer.
+  pub fn plays(&self, note: usize) -> bool {
+    self.enabled && (self.keys.0..=self.keys.1).contains(&note)
+  }
+}
+
+/// Which voice gives way when a note arrives and every voice is busy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StealPolicy {
+  /// The voice that was started first.
+  Oldest,
+  /// The voice whose envelope is currently lowest.
+  Quietest,
+  /// The voice playing the lowest note.
+  Lowest,
+  /// A note that is already sounding is retriggered on its own voice,
+  /// otherwise the oldest voice is taken.
+  SameNote,
+}
+
+/// Fixed pool of voices; a note needs a voice to be heard.
+pub struct VoiceAllocator {
+  voices: Box<[Voice]>,
+  policy: StealPolicy,
+  clock: u64,
+}
+
+impl VoiceAllocator {
+  pub fn new(polyphony: usize, policy: StealPolicy) -> Self {
+    let voice = Voice {
+      note: 0,
+      state: NoteState::Silent,
+      noise: NoteState::Silent,
+      started: 0,
+      held: false,
+      gain: 1.0,
+      brightness: 1.0,
+      velocity: 1.0,
+      layer: 0,
+    };
+    Self {
+      voices: vec![voice; polyphony].into_boxed_slice(),
+      policy,
+      clock: 0,
+    }
+  }
+  pub fn polyphony(&self) -> usize {
+    self.voices.len()
+  }
+  pub fn policy(&self) -> StealPolicy {
+    self.policy
+  }
+  pub fn set_policy(&mut self, policy: StealPolicy) {
+    self.policy = policy;
+  }
+  pub fn voices(&self) -> &[Voice] {
+    &self.voices
+  }
+  pub fn voices_mut(&mut self) -> &mut [Voice] {
+    &mut self.voices
+  }
+  pub fn active(&self) -> usize {
+    self.voices.iter().filter(|v| v.is_active()).count()
+  }
+  /// Starts `note` of `layer` played at `velocity` on a free voice, stealing
+  /// one if needed, and returns its index. The attack picks up from the level
+  /// the voice is currently at. `envelopes` holds the envelope of every layer.
+  pub fn start(
+    &mut self,
+    note: usize,
+    layer: usize,
+    velocity: f32,
+    (gain, brightness): (f32, f32),
+    envelopes: &[EnvelopeParams],
+    noise_envelope: &EnvelopeParams,
+  ) -> usize {
+    let i = self.pick(note, layer, envelopes);
+    self.clock += 1;
+    let voice = &mut self.voices[i];
+    let envelope = &envelopes[layer];
+    // a stolen voice picks up from the level of its own layer's envelope
+    let level = voice.state.peek(&envelopes[voice.layer]);
+    *voice = Voice {
+      note,
+      state: NoteState::attack_from(level, envelope),
+      noise: voice.noise.retrigger(noise_envelope),
+      started: self.clock,
+      held: false,
+      gain,
+      brightness,
+      velocity,
+      layer,
+    };
+    i
+  }
+  /// Releases every voice playing `note`, or marks them as held by the pedal.
+  pub fn release(
+    &mut self,
+    note: usize,
+    pedal: bool,
+    envelopes: &[EnvelopeParams],
+    noise_envelope: &EnvelopeParams,
+  ) {
+    for voice in self.voices.iter_mut() {
+      if voice.note != note || voice.state.is_releasing() {
+        continue;
+      }
+      if pedal {
+        voice.held = true;
+      } else {
+        voice.state = voice.state.release(&envelopes[voice.layer]);
+        voice.noise = voice.noise.release(noise_envelope);
+      }
+    }
+  }
+  /// Releases the voices that were kept sounding by the pedal.
+  pub fn release_held(&mut self, envelopes: &[EnvelopeParams], noise_envelope: &EnvelopeParams) {
+    for voice in self.voices.iter_mut().filter(|v| v.held) {
+      voice.held = false;
+      voice.state = voice.state.release(&envelopes[voice.layer]);
+      voice.noise = voice.noise.release(noise_envelope);
+    }
+  }
+  fn pick(&self, note: usize, layer: usize, envelopes: &[EnvelopeParams]) -> usize {
+    let voices = self.voices.iter().enumerate();
+    if self.policy == StealPolicy::SameNote {
+      // each layer of a key keeps its own voice
+      if let Some((i, _)) = voices
+        .clone()
+        .find(|(_, v)| v.is_active() && v.note == note && v.layer == layer)
+      {
+        return i;
+      }
+    }
+    if let Some((i, _)) = voices.clone().find(|(_, v)| !v.is_active()) {
+      return i;
+    }
+    let stolen = match self.policy {
+      StealPolicy::Oldest | StealPolicy::SameNote => voices.min_by_key(|(_, v)| v.started),
+      StealPolicy::Quietest => voices.min_by(|(_, a), (_, b)| {
+        let a = a.state.peek(&envelopes[a.layer]) * a.gain;
+        let b = b.state.peek(&envelopes[b.layer]) * b.gain;
+        a.total_cmp(&b)
+      }),
+      StealPolicy::Lowest => voices.min_by_key(|(_, v)| v.note),
+    };
+    stolen.map_or(0, |(i, _)| i)
+  }
+}
+
+#[test]
+fn test_steal_policies() {
+  let envelope = EnvelopeParams::default();
+  let mut voices = VoiceAllocator::new(2, StealPolicy::SameNote);
+  assert_eq!(
+    voices.start(40, 0, 1.0, (1.0, 1.0), &[envelope], &envelope),
+    0
+  );
+  assert_eq!(
+    voices.start(50, 0, 1.0, (1.0, 1.0), &[envelope], &envelope),
+    1
+  );
+  assert_eq!(
+    voices.start(40, 0, 1.0, (1.0, 1.0), &[envelope], &envelope),
+    0
+  );
+  assert_eq!(
+    voices.start(30, 0, 1.0, (1.0, 1.0), &[envelope], &envelope),
+    1
+  );
+  assert_eq!(voices.active(), 2);
+
+  voices.set_policy(StealPolicy::Lowest);
+  assert_eq!(
+    voices.start(60, 0, 1.0, (1.0, 1.0), &[envelope], &envelope),
+    1
+  );
+  assert_eq!(voices.voices()[1].note, 60);
+
+  voices.set_policy(StealPolicy::Oldest);
+  assert_eq!(
+    voices.start(70, 0, 1.0, (1.0, 1.0), &[envelope], &envelope),
+    0
+  );
+}
+
+#[test]
+fn test_sustain_pedal() {
+  let envelope = EnvelopeParams::adsr(1.0, 0.01, 0.1, 0.5, 0.2).unwrap();
+  let envelopes = [envelope];
+  let mut voices = VoiceAllocator::new(1, StealPolicy::SameNote);
+  // plays the only voice for a second and returns where it ends up
+  let play = |voices: &mut VoiceAllocator, pedal: bool| {
+    let state = &mut voices.voices_mut()[0].state;
+    for _ in 0..1000 {
+      state.next(&envelope, 0.001, pedal);
+    }
+    state.peek(&envelope)
+  };
+  voices.start(40, 0, 1.0, (1.0, 1.0), &envelopes, &envelope);
+  // a held key settles at the sustain level
+  assert!((play(&mut voices, false) - 0.5).abs() < 1e-4);
+  // key-up with the pedal down leaves it there
+  voices.release(40, true, &envelopes, &envelope);
+  assert!(voices.voices()[0].held);
+  assert!((play(&mut voices, true) - 0.5).abs() < 1e-4);
+  // lifting the pedal releases it
+  voices.release_held(&envelopes, &envelope);
+  assert!(!voices.voices()[0].held && voices.voices()[0].state.is_releasing());
+  assert_eq!(play(&mut voices, false), 0.0);
+  assert!(!voices.voices()[0].is_active());
+}
+
+#[test]
+fn test_layers() {
+  let bass = LayerParams {
+    enabled: true,
+    keys: (0, 47),
+    ..LayerParams::default()
+  };
+  assert!(bass.plays(47) && !bass.plays(48));
+  assert!(!LayerParams::default().plays(0));
+
+  // both layers of a key sound, each on a voice of its own
+  let envelopes = [EnvelopeParams::default(); 2];
+  let mut voices = VoiceAllocator::new(2, StealPolicy::SameNote);
+  assert_eq!(
+    voices.start(40, 0, 1.0, (1.0, 1.0), &envelopes, &envelopes[0]),
+    0
+  );
+  assert_eq!(
+    voices.start(40, 1, 1.0, (1.0, 1.0), &envelopes, &envelopes[0]),
+    1
+  );
+  assert_eq!(
+    voices.start(40, 1, 1.0, (1.0, 1.0), &envelopes, &envelopes[0]),
+    1
+  );
+  assert_eq!(voices.voices()[1].layer, 1);
+}
+
+#[test]
+fn test_unison_copies() {
+  let unison = UnisonParams {
+    voices: 3,
+    detune: 2400.0,
+    spread: 1.0,
+    random_phase: false,
+  };
+  assert_eq!(unison.copy(0), (0.5, -1.0));
+  assert_eq!(unison.copy(1), (1.0, 0.0));
+  assert_eq!(unison.copy(2), (2.0, 1.0));
+  assert_eq!(UnisonParams::default().copy(0), (1.0, 0.0));
+}
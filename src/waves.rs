@@ -1,102 +1,35 @@
-use crate::lerp::{inv_lerp, lerp, quadratic_interpolate_as};
+use crate::engine::{AudioState, Command, EngineParams, Param};
+use crate::envelope::{ControlClock, EnvelopeParams};
+use crate::filter::{FilterParams, Svf, SvfCoefs};
+use crate::fm::{FmParams, FmVoice};
+use crate::lfo::{LfoBank, LfoParams, LFO_COUNT};
+use crate::limiter::{gain_db, Limiter, LimiterParams};
+use crate::modulation::{
+  ModDestination, ModInputs, ModMatrix, ModSources, ModVoice, Route, MOD_ENVELOPES,
+};
+use crate::noise::{NoiseParams, Rng};
+use crate::pan::PanParams;
+use crate::partials::Partial;
+use crate::pitch::{pitch_ratio, BendGlide, PitchBend, VibratoParams};
+use crate::queue::{queue, Producer};
+use crate::voices::{
+  LayerParams, StealPolicy, UnisonParams, VelocityParams, VoiceAllocator, MAX_LAYERS, MAX_UNISON,
+};
+use crate::wavetable::Wavetable;
 use num::Complex;
 use rodio::Source;
 use rustfft::Fft;
 use std::{
-  cell::UnsafeCell,
+  collections::VecDeque,
+  f32::consts::{FRAC_PI_2, PI, TAU},
   sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    Arc, Mutex, MutexGuard,
   },
 };
 
-use crate::lerp::lerp_as;
 const CZERO: Complex<f32> = Complex { re: 0.0, im: 0.0 };
 
-#[derive(Debug, Clone, Copy)]
-pub enum NoteState {
-  Silent,
-  Attack(f32),
-  Decay(f32),
-  Sustain(f32),
-  Release(f32),
-}
-
-pub struct AdsrParams {
-  attack_level: f32,
-  sustain_level: f32,
-  attack_dur: f32,
-  decay_dur: f32,
-  release_dur: f32,
-  sustain_dur: f32,
-}
-
-impl NoteState {
-  #[inline(always)]
-  pub fn next(&mut self, adsr: &AdsrParams, dt: f32, sustain: bool) -> f32 {
-    use NoteState::*;
-    // let val;
-    // let next;
-    let (next, val) = match *self {
-      Silent => (Silent, 0.0),
-      Attack(t) if t > 0.0 => (
-        Attack(t - dt),
-        quadratic_interpolate_as(t, adsr.attack_dur, 0.0, 0.0, adsr.attack_level),
-      ),
-      Attack(_) => (Decay(adsr.decay_dur), adsr.attack_level),
-      Decay(t) if t > 0.0 => (
-        Decay(t - dt),
-        lerp_as(
-          t,
-          adsr.decay_dur,
-          0.0,
-          adsr.attack_level,
-          adsr.sustain_level,
-        ),
-      ),
-      Decay(_) => (
-        Sustain(if sustain {
-          adsr.sustain_dur
-        } else {
-          adsr.sustain_dur / 4.0
-        }),
-        adsr.sustain_level,
-      ),
-      Sustain(t) if t > 0.0 => (Sustain(t - dt), adsr.sustain_level),
-      Sustain(_) => (Release(adsr.release_dur), adsr.sustain_level),
-      Release(t) if t > 0.0 => (
-        Release(t - dt),
-        lerp_as(t, adsr.release_dur, 0.0, adsr.sustain_level, 0.0),
-      ),
-      Release(_) => (Silent, 0.0),
-    };
-    let self_ptr = self as *mut Self as *mut u64;
-    let next_ptr = &next as *const Self as *const u64;
-    unsafe { self_ptr.write_volatile(*next_ptr) };
-    val
-  }
-  #[inline(always)]
-  pub fn peek(&self, adsr: &AdsrParams) -> f32 {
-    use NoteState::*;
-    match *self {
-      Silent => 0.0,
-      Attack(t) if t > 0.0 => lerp_as(t, adsr.attack_dur, 0.0, 0.0, adsr.attack_level),
-      Attack(_) => adsr.attack_level,
-      Decay(t) if t > 0.0 => lerp_as(
-        t,
-        adsr.decay_dur,
-        0.0,
-        adsr.attack_level,
-        adsr.sustain_level,
-      ),
-      Decay(_) => adsr.sustain_level,
-      Sustain(_) => adsr.sustain_level,
-      Release(t) if t > 0.0 => lerp_as(t, adsr.release_dur, 0.0, adsr.sustain_level, 0.0),
-      Release(_) => 0.0,
-    }
-  }
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum NoteMode {
@@ -104,169 +37,1355 @@ pub enum NoteMode {
   Saw,
   Triangle,
   Square,
+  /// Harmonics taken from [`WavesControl::set_partials`].
+  Custom,
+  /// A band of noise centred on the note, see [`NoiseParams`].
+  Noise,
+  /// Operators from [`FmParams`], rendered in the time domain by both engines.
+  Fm,
+  /// Frames of a [`Wavetable`], morphed by [`WavesControl::set_table_position`].
+  Wavetable,
 }
 
 impl NoteMode {
-  pub fn calc(self, i: usize, n: usize, hn: usize, v: Complex<f32>, window: &mut [Complex<f32>]) {
+  pub const ALL: [NoteMode; 8] = [
+    NoteMode::Sine,
+    NoteMode::Saw,
+    NoteMode::Triangle,
+    NoteMode::Square,
+    NoteMode::Custom,
+    NoteMode::Noise,
+    NoteMode::Fm,
+    NoteMode::Wavetable,
+  ];
+  /// Name used on the command line and in presets.
+  pub fn name(self) -> &'static str {
+    match self {
+      NoteMode::Sine => "sine",
+      NoteMode::Saw => "saw",
+      NoteMode::Triangle => "triangle",
+      NoteMode::Square => "square",
+      NoteMode::Custom => "custom",
+      NoteMode::Noise => "noise",
+      NoteMode::Fm => "fm",
+      NoteMode::Wavetable => "wavetable",
+    }
+  }
+  pub fn from_name(name: &str) -> Option<Self> {
+    Self::ALL.into_iter().find(|m| m.name() == name)
+  }
+  /// Adds the spectrum of a note sitting at the (possibly fractional) `bin`.
+  pub fn calc(self, bin: f32, v: Complex<f32>, phase: f32, spectrum: &mut Spectrum) {
+    let harmonics = (spectrum.half_len() as f32 / bin).ceil() as usize;
+    let harmonics = ((harmonics - 1) as f32 * spectrum.brightness)
+      .ceil()
+      .max(1.0) as usize
+      + 1;
     match self {
       NoteMode::Sine => {
-        window[i + 1] -= v;
-        window[n - i - 1] += v;
+        spectrum.add_partial(bin, v * Complex::cis(phase));
       }
       NoteMode::Saw => {
-        for j in 1..hn / (i + 1) {
-          window[(i + 1) * j] -= v / (j as f32);
-          window[n - (i + 1) * j] += v / (j as f32);
+        for j in 1..harmonics {
+          let v = v / (j as f32) * Complex::cis(phase * j as f32);
+          spectrum.add_partial(bin * j as f32, v);
         }
       }
       NoteMode::Triangle => {
-        for j in (1..hn / (i + 1)).step_by(2) {
-          let other_odd = (-1f32).powi(j as i32/2);
-          window[(i + 1) * j] -= v / (j as f32).powi(2) * other_odd;
-          window[n - (i + 1) * j] += v / (j as f32).powi(2) * other_odd;
+        for j in (1..harmonics).step_by(2) {
+          let other_odd = (-1f32).powi(j as i32 / 2);
+          let v = v / (j as f32).powi(2) * other_odd * Complex::cis(phase * j as f32);
+          spectrum.add_partial(bin * j as f32, v);
         }
-      },
+      }
       NoteMode::Square => {
-        for j in (1..hn / (i + 1)).step_by(2) {
-          window[(i + 1) * j] -= v / (j as f32);
-          window[n - (i + 1) * j] += v / (j as f32);
+        // a pulse is a saw minus a copy of itself delayed by the width, which
+        // turns harmonic `j` into `sin(PI j d) / j`; at 50% the even ones cancel
+        let d = spectrum.pulse_width;
+        for j in 1..harmonics {
+          let j = j as f32;
+          let a = (PI * j * d).sin() / j;
+          if a.abs() < 1e-6 {
+            continue;
+          }
+          let v = v * a * Complex::cis(phase * j - PI * j * d + FRAC_PI_2);
+          spectrum.add_partial(bin * j, v);
+        }
+      }
+      NoteMode::Custom => {
+        let partials = spectrum.partials;
+        for (j, partial) in (1..harmonics).zip(partials) {
+          let j = j as f32;
+          let v = v * partial.amplitude * Complex::cis(phase * j + partial.phase);
+          spectrum.add_partial(bin * j, v);
+        }
+      }
+      NoteMode::Noise => {
+        let (lo, hi) = spectrum.noise.band(bin);
+        let slope = spectrum.noise.color.slope();
+        spectrum.add_noise(lo, hi, slope, v);
+      }
+      // has no spectrum of its own; the engines render it sample by sample
+      NoteMode::Fm => (),
+      NoteMode::Wavetable => {
+        let (table, position) = (spectrum.table, spectrum.table_position);
+        for j in 1..harmonics.min(table.harmonic_count() + 1) {
+          let v = v * table.harmonic(position, j) * Complex::cis(phase * j as f32);
+          spectrum.add_partial(bin * j as f32, v);
         }
       }
     }
   }
 }
 
-pub struct WavesControl {
-  pub ss: UnsafeCell<Box<[NoteState]>>,
-  pub mode: UnsafeCell<NoteMode>,
-  pub sustain: AtomicBool,
-  pub adsr: AdsrParams,
+/// Narrowest and widest pulse, as a fraction of the cycle.
+pub const PULSE_WIDTH_RANGE: (f32, f32) = (0.01, 0.99);
+
+/// Duty cycle of [`NoteMode::Square`] and its pulse-width modulation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PulseParams {
+  /// Fraction of the cycle spent high; 0.5 is a square.
+  pub width: f32,
+  /// How far the modulation swings the width to either side.
+  pub pwm_depth: f32,
+  /// Modulation speed in Hz.
+  pub pwm_rate: f32,
+}
+
+impl Default for PulseParams {
+  fn default() -> Self {
+    Self {
+      width: 0.5,
+      pwm_depth: 0.0,
+      pwm_rate: 0.0,
+    }
+  }
 }
 
-unsafe impl Send for WavesControl {}
-unsafe impl Sync for WavesControl {}
+impl PulseParams {
+  /// Width at `phase` (in cycles) of the modulation sweep.
+  pub fn width_at(&self, phase: f32) -> f32 {
+    let (min, max) = PULSE_WIDTH_RANGE;
+    (self.width + self.pwm_depth * (TAU * phase).sin()).clamp(min, max)
+  }
+}
+
+/// Window applied to every synthesized frame before it is overlap-added.
+///
+/// The window is built into the spectrum rather than multiplied in after the
+/// inverse transform: a partial is added as the transform of the windowed
+/// sinusoid, which lets it sit between bins. Hann and Blackman keep that
+/// leakage within a few bins, so they are the ones to use for exact pitch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SynthWindow {
+  Rectangular,
+  Hann,
+  Hamming,
+  Blackman,
+}
+
+impl SynthWindow {
+  /// Coefficients `a` of the window `a0 - a1 cos(x) + a2 cos(2x) - ...`.
+  pub fn terms(self) -> &'static [f32] {
+    match self {
+      SynthWindow::Rectangular => &[1.0],
+      SynthWindow::Hann => &[0.5, 0.5],
+      SynthWindow::Hamming => &[0.54, 0.46],
+      SynthWindow::Blackman => &[0.42, 0.5, 0.08],
+    }
+  }
+  /// Transform of the windowed complex exponential, `d` bins away from it.
+  #[inline(always)]
+  pub fn kernel(self, d: f32, n: usize) -> Complex<f32> {
+    let mut c = CZERO;
+    for (m, a) in self.terms().iter().enumerate() {
+      let a = if m % 2 == 0 { *a } else { -*a };
+      if m == 0 {
+        c += dirichlet(d, n) * a;
+      } else {
+        c += (dirichlet(d - m as f32, n) + dirichlet(d + m as f32, n)) * (a / 2.0);
+      }
+    }
+    c
+  }
+  /// How many bins on each side of a partial receive its leakage. The
+  /// rectangular window's sidelobes only fall off as `1 / d`, so cutting them
+  /// off as early as the tapered windows' would leave a step at the cut.
+  pub fn kernel_half_width(self) -> isize {
+    match self {
+      SynthWindow::Rectangular => 64,
+      _ => 8,
+    }
+  }
+  /// Whether frames `hop` samples apart add up to a constant gain. Every
+  /// cosine term of the window must cancel out across the overlapping frames,
+  /// which takes a whole number of them per frame and none of the term
+  /// frequencies being a multiple of that number.
+  pub fn overlaps_evenly(self, hop: usize, n: usize) -> bool {
+    if hop == 0 || !n.is_multiple_of(hop) {
+      return false;
+    }
+    let frames = n / hop;
+    let mut terms = self.terms().iter().enumerate().skip(1);
+    terms.all(|(m, a)| *a == 0.0 || !m.is_multiple_of(frames))
+  }
+}
+
+/// Normalized DFT over `n` samples of a complex exponential that is `d` bins
+/// away from the bin being evaluated.
+#[inline(always)]
+fn dirichlet(d: f32, n: usize) -> Complex<f32> {
+  if d.abs() < 1e-4 {
+    return Complex::new(1.0, 0.0);
+  }
+  let n = n as f32;
+  let x = PI * d;
+  Complex::from_polar(x.sin() / (n * (x / n).sin()), x * (1.0 - 1.0 / n))
+}
+
+/// Frequency-domain frame being assembled for the next inverse transform.
+///
+/// Both channels share one transform: the left channel ends up in the real
+/// part of the output and the right channel in the imaginary part.
+pub struct Spectrum<'a> {
+  pub bins: &'a mut [Complex<f32>],
+  pub window: SynthWindow,
+  /// Left gain in `re`, right gain in `im`, for the partials added next.
+  pub gains: Complex<f32>,
+  /// Fraction of the harmonics below Nyquist that are rendered.
+  pub brightness: f32,
+  /// Duty cycle used by [`NoteMode::Square`].
+  pub pulse_width: f32,
+  /// Harmonics used by [`NoteMode::Custom`].
+  pub partials: &'a [Partial],
+  pub table: &'a Wavetable,
+  /// Where [`NoteMode::Wavetable`] sits between the first and last frame.
+  pub table_position: f32,
+  pub noise: NoiseParams,
+  /// Makes up for frames of random-phase noise overlapping incoherently,
+  /// which leaves them quieter than a tone of the same amplitude.
+  pub noise_gain: f32,
+  pub rng: &'a mut Rng,
+}
+
+impl Spectrum<'_> {
+  pub fn half_len(&self) -> usize {
+    self.bins.len() / 2
+  }
+  /// Adds a partial at `bin` together with its conjugate mirror so that each
+  /// channel of the inverse transform stays real.
+  #[inline(always)]
+  pub fn add_partial(&mut self, bin: f32, v: Complex<f32>) {
+    let n = self.bins.len();
+    let base = bin.floor() as isize;
+    let width = self.window.kernel_half_width();
+    for k in base + 1 - width..=base + width {
+      if k < 1 || k as usize >= n / 2 {
+        continue;
+      }
+      self.add_at(k as usize, v * self.window.kernel(bin - k as f32, n));
+    }
+  }
+  /// [`Self::add_partial`] for a partial sitting exactly on bin `k`, which
+  /// only leaks into as many neighbours as the window has terms.
+  #[inline(always)]
+  pub fn add_bin(&mut self, k: usize, v: Complex<f32>) {
+    let n = self.bins.len();
+    for (m, a) in self.window.terms().iter().enumerate() {
+      let a = if m % 2 == 0 { *a } else { -*a };
+      if m == 0 {
+        self.add_at(k, v * a);
+        continue;
+      }
+      for k in [k.wrapping_sub(m), k + m] {
+        if (1..n / 2).contains(&k) {
+          self.add_at(k, v * (a / 2.0));
+        }
+      }
+    }
+  }
+  /// Noise between bins `lo` and `hi`: every bin in between gets a random
+  /// phase and an amplitude falling as `bin^-slope`, with the total power of a
+  /// partial of amplitude `|v|`.
+  pub fn add_noise(&mut self, lo: f32, hi: f32, slope: f32, v: Complex<f32>) {
+    let lo = (lo.ceil() as usize).max(1);
+    let hi = (hi.floor() as usize).min(self.half_len() - 1);
+    if lo > hi {
+      // narrower than a bin, so the nearest one stands in for the band
+      let k = lo.min(self.half_len() - 1);
+      let phase = self.rng.phase();
+      self.add_bin(k, v * self.noise_gain * Complex::cis(phase));
+      return;
+    }
+    let power: f32 = (lo..=hi).map(|k| (k as f32).powf(-2.0 * slope)).sum();
+    let v = v * self.noise_gain / power.sqrt();
+    for k in lo..=hi {
+      let phase = self.rng.phase();
+      self.add_bin(k, v * (k as f32).powf(-slope) * Complex::cis(phase));
+    }
+  }
+  /// The same settings, adding into `bins` instead.
+  pub fn with_bins<'b>(&'b mut self, bins: &'b mut [Complex<f32>]) -> Spectrum<'b> {
+    Spectrum {
+      bins,
+      window: self.window,
+      gains: self.gains,
+      brightness: self.brightness,
+      pulse_width: self.pulse_width,
+      partials: self.partials,
+      table: self.table,
+      table_position: self.table_position,
+      noise: self.noise,
+      noise_gain: self.noise_gain,
+      rng: &mut *self.rng,
+    }
+  }
+  #[inline(always)]
+  fn add_at(&mut self, k: usize, c: Complex<f32>) {
+    let n = self.bins.len();
+    self.bins[k] -= c * self.gains;
+    self.bins[n - k] -= c.conj() * self.gains;
+  }
+}
+
+/// Equal-tempered frequency of `note`, counting semitones up from C0.
+pub fn note_freq(note: usize) -> f32 {
+  2.0f32.powf(note as f32 / 12.0) * 16.35
+}
+
+/// Longest hop the builder accepts. Pitch moves once a hop, and overlapping
+/// frames only blend the steps into a glide when they come this often.
+pub const MAX_HOP_SECS: f32 = 0.025;
+
+/// Commands the queue holds; more wait with the control until there is room.
+const COMMAND_CAPACITY: usize = 1024;
+
+/// Sending end of the command queue, with the commands that found it full
+/// waiting their turn so that none is ever dropped.
+pub struct CommandSender {
+  producer: Producer<Command>,
+  waiting: VecDeque<Command>,
+  /// Shared settings replaced here, kept until the engine has let go of
+  /// them too so that the audio thread is never the one to free them.
+  retired: Vec<Param>,
+}
+
+impl CommandSender {
+  /// The command that has been waiting longest.
+  pub fn take(&mut self) -> Option<Command> {
+    self.waiting.pop_front()
+  }
+  /// Moves waiting commands into the queue, in order, while they fit.
+  fn flush(&mut self) {
+    while let Some(command) = self.waiting.pop_front() {
+      if let Err(command) = self.producer.push(command) {
+        self.waiting.push_front(command);
+        break;
+      }
+    }
+  }
+}
+
+/// What one voice was last seen playing, for [`WavesControl::get_state`].
+#[derive(Default)]
+struct VoiceMeter {
+  note: AtomicUsize,
+  /// Level scaled by the voice and layer gains, as `f32` bits.
+  level: AtomicU32,
+}
+
+/// Control surface shared by the UI and MIDI threads. Changes are queued for
+/// the engine, which owns the voices; settings are read back from the copy
+/// kept here, and what the engine plays comes back through atomics.
+pub struct WavesControl {
+  commands: Mutex<CommandSender>,
+  /// Some commands are waiting in `commands` for room in the queue.
+  waiting: AtomicBool,
+  /// Mouse position and MIDI controller levels as `f32` bits. Only the
+  /// latest value of each matters, so they skip the queue.
+  mouse: [AtomicU32; 2],
+  cc: Box<[AtomicU32]>,
+  /// `mouse` or `cc` moved since the engine last read them.
+  inputs_changed: AtomicBool,
+  /// The settings as last sent.
+  params: Mutex<EngineParams>,
+  /// Notes end on their own, so note-off is ignored.
+  pub auto_release: bool,
+  /// Lowest limiter gain since the meter was last read, as `f32` bits.
+  pub gain_reduction: AtomicU32,
+  meters: Box<[VoiceMeter]>,
+  active: AtomicUsize,
+  /// Latest samples played, mixed down to mono, as `f32` bits.
+  scope: Box<[AtomicU32]>,
+  /// Samples ever written to `scope`.
+  scope_pos: AtomicUsize,
+  pub sample_rate: u32,
+  pub frame_len: usize,
+  pub note_count: usize,
+}
 
 impl WavesControl {
-  pub fn hit(&self, note: usize) {
-    let ss = unsafe { &mut *self.ss.get() };
-    use NoteState::*;
-    let f = 2.0f32.powf(note as f32 / 12.0) * 16.35;
-    // println!("hit freq: {f}");
-    let index = f as usize - 16;
-    // println!("hit index: {index}");
-    if index < ss.len() {
-      let note = ss[index + 1];
-      let v = note.peek(&self.adsr);
-      let v = inv_lerp(v, 0.0, self.adsr.attack_level);
-      let v = lerp(v, self.adsr.attack_dur, 0.0);
-      ss[index + 1] = Attack(v);
+  fn send(&self, command: Command) {
+    self.send_replacing(command, None);
+  }
+  /// Sends `command` once the settings kept here no longer hold `replaced`.
+  fn send_replacing(&self, command: Command, replaced: Option<Param>) {
+    let mut sender = self.commands.lock().unwrap();
+    sender.retired.retain(Param::is_shared);
+    sender.retired.extend(replaced.filter(Param::is_shared));
+    sender.waiting.push_back(command);
+    sender.flush();
+    self
+      .waiting
+      .store(!sender.waiting.is_empty(), Ordering::Release);
+  }
+  /// Some commands found the queue full and are waiting for room.
+  pub fn has_waiting(&self) -> bool {
+    self.waiting.load(Ordering::Acquire)
+  }
+  /// The commands that found the queue full, for the engine to take every
+  /// one of once it has emptied the queue. `None` if there are none, or if
+  /// another thread is sending and the audio thread would have to wait.
+  pub fn waiting(&self) -> Option<MutexGuard<'_, CommandSender>> {
+    if !self.has_waiting() {
+      return None;
+    }
+    let sender = self.commands.try_lock().ok()?;
+    self.waiting.store(false, Ordering::Relaxed);
+    Some(sender)
+  }
+  /// Copies the mouse and controller levels into `inputs` if they moved
+  /// since the last call.
+  pub fn read_inputs(&self, inputs: &mut ModInputs) {
+    if !self.inputs_changed.swap(false, Ordering::Acquire) {
+      return;
+    }
+    let load = |x: &AtomicU32| f32::from_bits(x.load(Ordering::Relaxed));
+    inputs.mouse = self.mouse.each_ref().map(load);
+    for (level, cc) in inputs.cc.iter_mut().zip(self.cc.iter()) {
+      *level = load(cc);
     }
   }
+  fn params(&self) -> MutexGuard<'_, EngineParams> {
+    self.params.lock().unwrap()
+  }
+  /// Applies the setting `change` makes to the ones kept here and sends the
+  /// engine the same, under one lock so that every thread's changes arrive
+  /// in order.
+  fn change(&self, change: impl FnOnce(&mut EngineParams) -> Param) {
+    let mut params = self.params();
+    let param = change(&mut params);
+    let replaced = params.set(param.clone());
+    self.send_replacing(Command::SetParam(param), replaced);
+  }
+  fn set(&self, param: Param) {
+    self.change(|_| param);
+  }
+  /// Starts `note` with `velocity` between 0 and 1 on every layer playing it.
+  pub fn hit(&self, note: usize, velocity: f32) {
+    if note < self.note_count {
+      self.send(Command::NoteOn { note, velocity });
+    }
+  }
+  /// Note-off: the note is released now, or when the pedal lifts if it is down.
+  pub fn release(&self, note: usize) {
+    self.send(Command::NoteOff { note });
+  }
+  /// Sustain pedal: while down, released notes keep sounding.
+  pub fn set_pedal(&self, down: bool) {
+    self.send(Command::Pedal(down));
+  }
+  /// Fills `freqs` with the current amplitude of every bin, starting at bin 1.
   pub fn get_state(&self, freqs: &mut [f32]) {
-    let ss = unsafe { &mut *self.ss.get() };
-    for (o, f) in freqs.iter_mut().zip(ss.iter()) {
-      *o = f.peek(&self.adsr);
+    let bin_hz = self.sample_rate as f32 / self.frame_len as f32;
+    freqs.fill(0.0);
+    for meter in self.meters.iter() {
+      let note = meter.note.load(Ordering::Relaxed);
+      let bin = (note_freq(note) / bin_hz).round() as usize;
+      if let Some(o) = bin.checked_sub(1).and_then(|i| freqs.get_mut(i)) {
+        *o += f32::from_bits(meter.level.load(Ordering::Relaxed));
+      }
+    }
+  }
+  /// Records what every voice is playing for [`WavesControl::get_state`].
+  pub fn publish(&self, voices: &VoiceAllocator, layers: &[LayerParams]) {
+    for (meter, voice) in self.meters.iter().zip(voices.voices()) {
+      let layer = &layers[voice.layer];
+      let level = voice.state.peek(&layer.envelope) * voice.gain * layer.volume;
+      meter.note.store(voice.note, Ordering::Relaxed);
+      meter.level.store(level.to_bits(), Ordering::Relaxed);
+    }
+    self.active.store(voices.active(), Ordering::Relaxed);
+  }
+  /// Adds one output sample to the scope.
+  pub fn record(&self, sample: f32) {
+    let pos = self.scope_pos.load(Ordering::Relaxed);
+    self.scope[pos % self.scope.len()].store(sample.to_bits(), Ordering::Relaxed);
+    self.scope_pos.store(pos.wrapping_add(1), Ordering::Relaxed);
+  }
+  /// Fills `out` with the latest samples played, oldest first.
+  pub fn scope(&self, out: &mut [f32]) {
+    let len = self.scope.len();
+    let end = self.scope_pos.load(Ordering::Relaxed);
+    let start = end.wrapping_sub(out.len().min(len));
+    for (i, o) in out.iter_mut().enumerate() {
+      let bits = self.scope[start.wrapping_add(i) % len].load(Ordering::Relaxed);
+      *o = f32::from_bits(bits);
     }
   }
   pub fn max_note(&self) -> usize {
-    let ss = unsafe { &*self.ss.get() };
-    let max_freq = ss.len();
-    ((max_freq as f32 / 16.35).log2() * 12.0) as usize
+    self.note_count - 1
+  }
+  /// Number of voices that are currently sounding.
+  pub fn active_voices(&self) -> usize {
+    self.active.load(Ordering::Relaxed)
+  }
+  pub fn polyphony(&self) -> usize {
+    self.meters.len()
+  }
+  pub fn layers(&self) -> [LayerParams; MAX_LAYERS] {
+    self.params().layers
+  }
+  /// Sounding notes of the layer carry on under its new envelope from the
+  /// level they are at. The envelope keeps the auto-release setting the
+  /// engine was built with.
+  pub fn set_layer(&self, index: usize, mut layer: LayerParams) {
+    if index < MAX_LAYERS {
+      layer.envelope = layer.envelope.with_auto_release(self.auto_release);
+      self.set(Param::Layer(index, layer));
+    }
+  }
+  /// Changes the envelope of layer `index`, see [`WavesControl::set_layer`].
+  pub fn set_envelope(&self, index: usize, envelope: EnvelopeParams) {
+    if let Some(&layer) = self.layers().get(index) {
+      self.set_layer(index, LayerParams { envelope, ..layer });
+    }
+  }
+  /// Mode of the first layer, the only one playing unless others are enabled.
+  pub fn mode(&self) -> NoteMode {
+    self.layers()[0].mode
+  }
+  pub fn set_mode(&self, mode: NoteMode) {
+    let mut params = self.params();
+    params.layers[0].mode = mode;
+    self.send(Command::SetMode(mode));
+  }
+  /// Splits the keyboard at `note`: the first layer plays below it and the
+  /// second, turned on if it was off, from it up. `None` gives every layer
+  /// the whole keyboard again.
+  pub fn set_split(&self, note: Option<usize>) {
+    self.set(Param::Split(note));
+  }
+  /// Places `note` between -1 (left) and 1 (right), before keyboard spread.
+  pub fn set_pan(&self, note: usize, pan: f32) {
+    if note < self.note_count {
+      self.set(Param::NotePan(note, pan.clamp(-1.0, 1.0)));
+    }
+  }
+  pub fn velocity(&self) -> VelocityParams {
+    self.params().velocity
+  }
+  /// How velocity sets the level and brightness of the notes started next.
+  pub fn set_velocity(&self, velocity: VelocityParams) {
+    self.set(Param::Velocity(velocity));
+  }
+  pub fn panning(&self) -> PanParams {
+    self.params().pan
+  }
+  /// Pan law and keyboard spread shared by every note.
+  pub fn set_panning(&self, pan: PanParams) {
+    self.set(Param::Pan(pan));
+  }
+  pub fn pulse(&self) -> PulseParams {
+    self.params().pulse
+  }
+  pub fn set_pulse(&self, pulse: PulseParams) {
+    self.set(Param::Pulse(pulse));
+  }
+  /// Sets the duty cycle of [`NoteMode::Square`], from 1% to 99%.
+  pub fn set_pulse_width(&self, width: f32) {
+    let (min, max) = PULSE_WIDTH_RANGE;
+    self.change(|params| {
+      params.pulse.width = width.clamp(min, max);
+      Param::Pulse(params.pulse)
+    });
+  }
+  pub fn pulse_width(&self) -> f32 {
+    self.params().pulse.width
+  }
+  /// Sweeps the pulse width by `depth` to either side, `rate` times a second.
+  pub fn set_pwm(&self, depth: f32, rate: f32) {
+    self.change(|params| {
+      params.pulse.pwm_depth = depth.clamp(0.0, 0.5);
+      params.pulse.pwm_rate = rate.max(0.0);
+      Param::Pulse(params.pulse)
+    });
+  }
+  /// Harmonics of [`NoteMode::Custom`], the fundamental first.
+  pub fn partials(&self) -> Arc<[Partial]> {
+    Arc::clone(&self.params().partials)
+  }
+  pub fn set_partials(&self, partials: impl Into<Arc<[Partial]>>) {
+    self.set(Param::Partials(partials.into()));
+  }
+  pub fn noise(&self) -> NoiseParams {
+    self.params().noise
+  }
+  pub fn set_noise(&self, noise: NoiseParams) {
+    self.set(Param::Noise(noise));
+  }
+  pub fn noise_envelope(&self) -> EnvelopeParams {
+    self.params().noise_envelope
+  }
+  /// Envelope of the noise layer, which sounding notes carry on under from
+  /// the level they are at like [`WavesControl::set_layer`].
+  pub fn set_noise_envelope(&self, envelope: EnvelopeParams) {
+    let envelope = envelope.with_auto_release(self.auto_release);
+    self.set(Param::NoiseEnvelope(envelope));
+  }
+  pub fn set_wavetable(&self, table: Wavetable) {
+    self.set(Param::Wavetable(Arc::new(table)));
+  }
+  pub fn table_position(&self) -> f32 {
+    self.params().table_position
+  }
+  /// Morphs [`NoteMode::Wavetable`] from its first frame (0) to its last (1).
+  pub fn set_table_position(&self, position: f32) {
+    self.set(Param::TablePosition(position.clamp(0.0, 1.0)));
+  }
+  pub fn bend(&self) -> PitchBend {
+    self.params().bend
+  }
+  /// Bends every voice, from -1 (down by the full range) to 1 (up by it).
+  pub fn set_bend(&self, amount: f32) {
+    self.change(|params| {
+      params.bend.amount = amount.clamp(-1.0, 1.0);
+      Param::Bend(params.bend)
+    });
+  }
+  pub fn set_bend_range(&self, semitones: f32) {
+    self.change(|params| {
+      params.bend.range = semitones.abs();
+      Param::Bend(params.bend)
+    });
+  }
+  pub fn vibrato(&self) -> VibratoParams {
+    self.params().vibrato
+  }
+  pub fn set_vibrato(&self, vibrato: VibratoParams) {
+    self.set(Param::Vibrato(vibrato));
+  }
+  pub fn lfos(&self) -> [LfoParams; LFO_COUNT] {
+    self.params().lfos
+  }
+  pub fn set_lfo(&self, index: usize, lfo: LfoParams) {
+    if index < LFO_COUNT {
+      self.set(Param::Lfo(index, lfo));
+    }
+  }
+  pub fn tempo(&self) -> f32 {
+    self.params().tempo
+  }
+  pub fn set_tempo(&self, bpm: f32) {
+    self.set(Param::Tempo(bpm.max(1.0)));
+  }
+  pub fn filter(&self) -> FilterParams {
+    self.params().filter
+  }
+  pub fn set_filter(&self, filter: FilterParams) {
+    self.set(Param::Filter(filter));
+  }
+  pub fn master(&self) -> f32 {
+    self.params().master
+  }
+  pub fn set_master(&self, gain: f32) {
+    self.set(Param::Master(gain.max(0.0)));
+  }
+  pub fn limiter(&self) -> LimiterParams {
+    self.params().limiter
+  }
+  pub fn set_limiter(&self, limiter: LimiterParams) {
+    self.set(Param::Limiter(limiter));
+  }
+  /// Deepest gain reduction of the limiter since the last call, in dB: zero
+  /// while it is idle, negative while it holds the output down.
+  pub fn gain_reduction(&self) -> f32 {
+    let bits = self.gain_reduction.swap(1f32.to_bits(), Ordering::Relaxed);
+    gain_db(f32::from_bits(bits))
+  }
+  /// Records the limiter gain of one output sample for the meter.
+  pub fn meter(&self, gain: f32) {
+    // positive floats are ordered the same way as their bits
+    self
+      .gain_reduction
+      .fetch_min(gain.max(0.0).to_bits(), Ordering::Relaxed);
+  }
+  pub fn mod_matrix(&self) -> Arc<ModMatrix> {
+    Arc::clone(&self.params().mod_matrix)
+  }
+  pub fn set_mod_matrix(&self, matrix: ModMatrix) {
+    self.set(Param::ModMatrix(Arc::new(matrix)));
+  }
+  /// Sends a copy of the matrix with `edit` made to its routes.
+  fn edit_routes(&self, edit: impl FnOnce(&mut Vec<Route>)) {
+    self.change(|params| {
+      let mut matrix = ModMatrix::clone(&params.mod_matrix);
+      edit(&mut matrix.routes);
+      Param::ModMatrix(Arc::new(matrix))
+    });
+  }
+  pub fn add_route(&self, route: Route) {
+    self.edit_routes(|routes| routes.push(route));
+  }
+  /// Takes out route `index`, counting from 0 in the order they were added.
+  pub fn remove_route(&self, index: usize) {
+    self.edit_routes(|routes| {
+      if index < routes.len() {
+        routes.remove(index);
+      }
+    });
+  }
+  pub fn set_route_depth(&self, index: usize, depth: f32) {
+    self.edit_routes(|routes| {
+      if let Some(route) = routes.get_mut(index) {
+        route.depth = depth;
+      }
+    });
+  }
+  pub fn mod_envelopes(&self) -> [EnvelopeParams; MOD_ENVELOPES] {
+    self.params().mod_envelopes
+  }
+  /// Keeps the auto-release setting like [`WavesControl::set_layer`].
+  pub fn set_mod_envelope(&self, index: usize, envelope: EnvelopeParams) {
+    if index < MOD_ENVELOPES {
+      let envelope = envelope.with_auto_release(self.auto_release);
+      self.set(Param::ModEnvelope(index, envelope));
+    }
+  }
+  /// Mouse position across the window, each axis from 0 to 1.
+  pub fn set_mouse(&self, x: f32, y: f32) {
+    for (axis, value) in self.mouse.iter().zip([x, y]) {
+      axis.store(value.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+    self.inputs_changed.store(true, Ordering::Release);
+  }
+  /// Level of MIDI controller `cc`, from 0 to 1.
+  pub fn set_cc(&self, cc: u8, value: f32) {
+    let level = value.clamp(0.0, 1.0).to_bits();
+    self.cc[(cc & 0x7F) as usize].store(level, Ordering::Relaxed);
+    self.inputs_changed.store(true, Ordering::Release);
+  }
+  pub fn fm(&self) -> FmParams {
+    *self.params().fm
+  }
+  pub fn set_fm(&self, fm: FmParams) {
+    self.set(Param::Fm(Arc::new(fm)));
+  }
+  pub fn unison(&self) -> UnisonParams {
+    self.params().unison
+  }
+  pub fn set_unison(&self, unison: UnisonParams) {
+    self.set(Param::Unison(unison));
   }
 }
-pub struct Waves {
-  fft: Arc<dyn Fft<f32>>,
-  window: Box<[Complex<f32>]>,
-  buf: Box<[Complex<f32>]>,
-  wp: usize,
-  control: Arc<WavesControl>,
+/// How consecutive IFFT frames are joined into the output stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synthesis {
+  /// Frames are played back to back, so the pitch steps from one to the next
+  /// and the frames have to be short. Unwindowed, every partial is spread
+  /// over 128 bins rather than 16, which makes each frame several times
+  /// dearer to build.
+  Block,
+  /// A new windowed frame is started every `hop` samples and summed with the
+  /// tails of the previous ones.
+  OverlapAdd { hop: usize, window: SynthWindow },
 }
 
-impl Waves {
-  pub fn new(notes: usize) -> Self {
+/// Configures a [`Waves`] engine. Sample rate, FFT frame size and the rate at
+/// which envelopes are stepped are all independent of each other.
+#[derive(Debug, Clone, Copy)]
+pub struct WavesBuilder {
+  sample_rate: u32,
+  frame_len: usize,
+  control_rate: Option<f32>,
+  synthesis: Option<Synthesis>,
+  polyphony: usize,
+  steal_policy: StealPolicy,
+  auto_release: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WavesBuilderError {
+  ZeroSampleRate,
+  /// The Nyquist frequency lies below the lowest playable note.
+  SampleRateTooLow(u32),
+  FrameTooShort(usize),
+  /// The hop is zero or longer than the frame.
+  InvalidHop {
+    hop: usize,
+    frame_len: usize,
+  },
+  /// The hop is longer than [`MAX_HOP_SECS`], so bends and vibrato would step.
+  HopTooLong {
+    hop: usize,
+    sample_rate: u32,
+  },
+  /// Frames of `window` spaced `hop` apart do not sum to a constant gain.
+  UnevenOverlap {
+    hop: usize,
+    frame_len: usize,
+    window: SynthWindow,
+  },
+  InvalidControlRate(f32),
+  /// Envelopes can not be stepped more often than once per sample.
+  ControlRateAboveSampleRate {
+    control_rate: f32,
+    sample_rate: u32,
+  },
+  ZeroPolyphony,
+}
+
+impl std::fmt::Display for WavesBuilderError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    use WavesBuilderError::*;
+    match self {
+      ZeroSampleRate => write!(f, "sample rate must be positive"),
+      SampleRateTooLow(sr) => write!(f, "sample rate {sr} Hz can not reproduce any note"),
+      FrameTooShort(len) => write!(f, "frame of {len} samples is too short"),
+      InvalidHop { hop, frame_len } => {
+        write!(f, "hop must be within 1..={frame_len}, got {hop}")
+      }
+      HopTooLong { hop, sample_rate } => write!(
+        f,
+        "hop of {hop} samples is longer than {MAX_HOP_SECS} s at {sample_rate} Hz"
+      ),
+      UnevenOverlap {
+        hop,
+        frame_len,
+        window,
+      } => write!(
+        f,
+        "{window:?} frames of {frame_len} samples do not overlap evenly {hop} samples apart"
+      ),
+      InvalidControlRate(rate) => write!(f, "control rate must be positive, got {rate}"),
+      ControlRateAboveSampleRate {
+        control_rate,
+        sample_rate,
+      } => write!(
+        f,
+        "control rate {control_rate} Hz exceeds sample rate {sample_rate} Hz"
+      ),
+      ZeroPolyphony => write!(f, "at least one voice is needed"),
+    }
+  }
+}
+
+impl std::error::Error for WavesBuilderError {}
+
+impl Default for WavesBuilder {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl WavesBuilder {
+  pub fn new() -> Self {
+    Self {
+      sample_rate: 44100,
+      frame_len: 2048,
+      control_rate: None,
+      synthesis: None,
+      polyphony: 32,
+      steal_policy: StealPolicy::SameNote,
+      auto_release: false,
+    }
+  }
+  pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+    self.sample_rate = sample_rate;
+    self
+  }
+  /// Length of the inverse FFT; sets the spacing of the bins.
+  pub fn frame_len(mut self, frame_len: usize) -> Self {
+    self.frame_len = frame_len;
+    self
+  }
+  /// How many times per second envelopes are stepped, with levels going in a
+  /// straight line in between. Defaults to every sample.
+  pub fn control_rate(mut self, control_rate: f32) -> Self {
+    self.control_rate = Some(control_rate);
+    self
+  }
+  /// Notes start and settings change at the start of a hop, and so do the
+  /// pitch, the LFOs and every modulation destination but the amplitude, so
+  /// a shorter hop answers sooner but builds more frames a second. Defaults
+  /// to Hann frames a quarter of a frame apart.
+  pub fn synthesis(mut self, synthesis: Synthesis) -> Self {
+    self.synthesis = Some(synthesis);
+    self
+  }
+  /// Maximum number of notes sounding at once.
+  pub fn polyphony(mut self, polyphony: usize) -> Self {
+    self.polyphony = polyphony;
+    self
+  }
+  pub fn steal_policy(mut self, steal_policy: StealPolicy) -> Self {
+    self.steal_policy = steal_policy;
+    self
+  }
+  /// Release notes after a fixed sustain time rather than on note-off.
+  pub fn auto_release(mut self, auto_release: bool) -> Self {
+    self.auto_release = auto_release;
+    self
+  }
+  pub fn build(self) -> Result<Waves, WavesBuilderError> {
+    use WavesBuilderError::*;
+    let Self {
+      sample_rate,
+      frame_len,
+      control_rate,
+      synthesis,
+      polyphony,
+      steal_policy,
+      auto_release,
+    } = self;
+    if sample_rate == 0 {
+      return Err(ZeroSampleRate);
+    }
+    let nyquist = sample_rate as f32 / 2.0;
+    if nyquist <= note_freq(0) {
+      return Err(SampleRateTooLow(sample_rate));
+    }
+    if frame_len < 4 {
+      return Err(FrameTooShort(frame_len));
+    }
+    let synthesis = synthesis.unwrap_or(Synthesis::OverlapAdd {
+      hop: frame_len / 4,
+      window: SynthWindow::Hann,
+    });
+    let (hop, synth_window) = match synthesis {
+      Synthesis::Block => (frame_len, SynthWindow::Rectangular),
+      Synthesis::OverlapAdd { hop, window } => (hop, window),
+    };
+    if hop == 0 || hop > frame_len {
+      return Err(InvalidHop { hop, frame_len });
+    }
+    if !synth_window.overlaps_evenly(hop, frame_len) {
+      return Err(UnevenOverlap {
+        hop,
+        frame_len,
+        window: synth_window,
+      });
+    }
+    let control_rate = control_rate.unwrap_or(sample_rate as f32);
+    if !control_rate.is_finite() || control_rate <= 0.0 {
+      return Err(InvalidControlRate(control_rate));
+    }
+    if control_rate > sample_rate as f32 {
+      return Err(ControlRateAboveSampleRate {
+        control_rate,
+        sample_rate,
+      });
+    }
+    if hop as f32 > MAX_HOP_SECS * sample_rate as f32 {
+      return Err(HopTooLong { hop, sample_rate });
+    }
+    if polyphony == 0 {
+      return Err(ZeroPolyphony);
+    }
+
+    // scale the window so that overlapping frames add up to unit gain
+    let terms = synth_window.terms();
+    let gain = hop as f32 / (frame_len as f32 * terms[0]);
+    // frames of noise add up in power rather than amplitude, so they only reach
+    // unit gain with the mean square of the window in place of its mean squared
+    let mean_square = terms[0].powi(2) + terms[1..].iter().map(|a| a * a / 2.0).sum::<f32>();
+    let noise_gain = terms[0] * (frame_len as f32 / (hop as f32 * mean_square)).sqrt();
     let mut planner = rustfft::FftPlanner::<f32>::new();
-    let fft = planner.plan_fft_inverse(notes);
+    let fft = planner.plan_fft_inverse(frame_len);
     let window = vec![CZERO; fft.len()].into_boxed_slice();
     let buf = vec![CZERO; fft.get_inplace_scratch_len()].into_boxed_slice();
-    let ss = UnsafeCell::new(vec![NoteState::Silent; fft.len() / 2 - 2].into_boxed_slice());
+    let note_count = ((nyquist / note_freq(0)).log2() * 12.0) as usize + 1;
+    let (producer, commands) = queue(COMMAND_CAPACITY);
+    let params = EngineParams::new(note_count, auto_release);
     let control = Arc::new(WavesControl {
-      ss,
-      sustain: AtomicBool::new(false),
-      mode: UnsafeCell::new(NoteMode::Sine),
-      adsr: AdsrParams {
-        attack_level: 0.4,
-        sustain_level: 0.3,
-        attack_dur: 0.2,
-        decay_dur: 0.04,
-        release_dur: 0.15,
-        sustain_dur: 0.2,
-      },
+      commands: Mutex::new(CommandSender {
+        producer,
+        waiting: VecDeque::new(),
+        retired: Vec::new(),
+      }),
+      waiting: AtomicBool::new(false),
+      mouse: Default::default(),
+      cc: (0..128).map(|_| AtomicU32::new(0)).collect(),
+      inputs_changed: AtomicBool::new(false),
+      params: Mutex::new(params.clone()),
+      auto_release,
+      gain_reduction: AtomicU32::new(1f32.to_bits()),
+      meters: (0..polyphony).map(|_| VoiceMeter::default()).collect(),
+      active: AtomicUsize::new(0),
+      scope: (0..frame_len).map(|_| AtomicU32::new(0)).collect(),
+      scope_pos: AtomicUsize::new(0),
+      sample_rate,
+      frame_len,
+      note_count,
     });
-    Self {
+    let voices = VoiceAllocator::new(polyphony, steal_policy);
+    Ok(Waves {
       fft,
       window,
       buf,
+      phases: vec![[0.0; MAX_UNISON]; polyphony].into_boxed_slice(),
+      started: vec![0; polyphony].into_boxed_slice(),
+      ages: vec![0.0; polyphony].into_boxed_slice(),
+      moving: vec![Moving::new(frame_len); 2 * polyphony].into_boxed_slice(),
+      fm_voices: vec![[FmVoice::default(); MAX_UNISON]; polyphony].into_boxed_slice(),
+      mod_voices: vec![ModVoice::default(); polyphony].into_boxed_slice(),
+      mod_levels: std::array::from_fn(|_| vec![0.0; frame_len].into_boxed_slice()),
+      mod_gains: vec![1.0; frame_len].into_boxed_slice(),
+      time: vec![CZERO; hop].into_boxed_slice(),
+      out: vec![CZERO; frame_len].into_boxed_slice(),
+      synth_window,
+      gain,
+      noise_gain,
+      rng: Rng::new(0x9E37_79B9),
+      hop,
+      clock: ControlClock::new(sample_rate, control_rate),
+      pwm_phase: 0.0,
+      bend: BendGlide::default(),
+      lfos: LfoBank::default(),
+      lowpass: [Svf::default(); 2],
+      limiter: Limiter::new(sample_rate),
       wp: 0,
-      control,
-    }
+      right: None,
+      audio: AudioState::new(voices, params, commands, control),
+    })
   }
-  pub fn shallow_clone(&self) -> Self {
-    let buf = vec![CZERO; self.buf.len()].into_boxed_slice();
-    let window = vec![CZERO; self.window.len()].into_boxed_slice();
+}
+
+/// A sound given its own transform so that its level can follow the
+/// envelope sample by sample.
+#[derive(Clone)]
+struct Moving {
+  bins: Box<[Complex<f32>]>,
+  /// Level for every sample of the frame.
+  levels: Box<[f32]>,
+}
+
+impl Moving {
+  fn new(frame_len: usize) -> Self {
     Self {
-      fft: Arc::clone(&self.fft),
-      window,
-      buf,
-      wp: 0,
-      control: self.control(),
+      bins: vec![CZERO; frame_len].into_boxed_slice(),
+      levels: vec![0.0; frame_len].into_boxed_slice(),
     }
   }
+}
+
+/// The first level and whether any other differs from it.
+fn level_span(levels: &[f32]) -> (f32, bool) {
+  let first = levels[0];
+  (first, levels.iter().any(|level| *level != first))
+}
+
+pub struct Waves {
+  fft: Arc<dyn Fft<f32>>,
+  window: Box<[Complex<f32>]>,
+  buf: Box<[Complex<f32>]>,
+  /// Per voice, one phase for every unison copy.
+  phases: Box<[[f32; MAX_UNISON]]>,
+  /// Per voice, `Voice::started` of the note last seen, to spot new notes.
+  started: Box<[u64]>,
+  /// Per voice, seconds since its note started, for the vibrato delay.
+  ages: Box<[f32]>,
+  /// Sounds whose level moves within the frame, two for every voice.
+  moving: Box<[Moving]>,
+  fm_voices: Box<[[FmVoice; MAX_UNISON]]>,
+  mod_voices: Box<[ModVoice]>,
+  /// Levels of the modulation envelopes of the voice being rendered, and
+  /// the gain its amplitude routes add up to, for every sample of the frame.
+  mod_levels: [Box<[f32]>; MOD_ENVELOPES],
+  mod_gains: Box<[f32]>,
+  /// Samples of the next hop from voices that have no spectrum.
+  time: Box<[Complex<f32>]>,
+  synth_window: SynthWindow,
+  gain: f32,
+  noise_gain: f32,
+  rng: Rng,
+  out: Box<[Complex<f32>]>,
+  hop: usize,
+  clock: ControlClock,
+  pwm_phase: f32,
+  bend: BendGlide,
+  lfos: LfoBank,
+  /// Filters the voices rendered in the time domain, left and right.
+  lowpass: [Svf; 2],
+  limiter: Limiter,
+  wp: usize,
+  right: Option<f32>,
+  audio: AudioState,
+}
+
+impl Waves {
+  /// `notes` bins in frames a sixteenth of a second long.
+  pub fn new(notes: usize) -> Self {
+    WavesBuilder::new()
+      .frame_len(notes)
+      .sample_rate(notes as u32 * 16)
+      .build()
+      .expect("invalid frame length")
+  }
+  pub fn builder() -> WavesBuilder {
+    WavesBuilder::new()
+  }
   pub fn control(&self) -> Arc<WavesControl> {
-    Arc::clone(&self.control)
+    Arc::clone(self.audio.control())
+  }
+  /// Hands the voices and the command queue over to another engine.
+  pub fn into_audio(self) -> AudioState {
+    self.audio
   }
 }
 
 impl Waves {
-  pub fn peek(&mut self) -> Option<f32> {
-    Some(self.calc(false))
-  }
-  pub fn calc(&mut self, progress: bool) -> f32 {
+  /// Next interleaved sample, left channel first.
+  pub fn calc(&mut self) -> f32 {
+    if let Some(r) = self.right.take() {
+      return r;
+    }
     let n = self.window.len();
-    let hn = n / 2;
-    if self.wp == n {
-      let ss = unsafe { &mut *self.control.ss.get() };
-      let sustain = self.control.sustain.load(Ordering::Relaxed);
-      let mode = unsafe { *self.control.mode.get() };
-      let dt = 1.0 / 16.0;
+    let hop = self.hop;
+    if self.wp == hop {
+      self.audio.update();
+      self.audio.publish();
+      let sample_rate = self.sample_rate() as f32;
+      let AudioState {
+        voices,
+        params,
+        sustain,
+        ..
+      } = &mut self.audio;
+      let sustain = *sustain;
+      let layers = &params.layers;
+      let pulse = params.pulse;
+      let noise = params.noise;
+      let noise_envelope = &params.noise_envelope;
+      let fm = &params.fm;
+      let unison = params.unison;
+      // pitch moves once a hop, and overlapping frames blend the steps
+      let step = hop as f32 / sample_rate;
+      self.pwm_phase = (self.pwm_phase + pulse.pwm_rate * step).fract();
+      // envelopes are looked ahead over the whole frame, but only the steps
+      // before the next one starts are kept
+      let commit = hop;
+      let bend = self.bend.next(&params.bend, step);
+      let vibrato = params.vibrato;
+      let lfos = self
+        .lfos
+        .next(&params.lfos, voices.voices(), params.tempo, step);
+      let matrix = &params.mod_matrix;
+      let mod_envelopes = params.mod_envelopes;
+      let sources = ModSources::global(&lfos, &params.mod_inputs);
+      let mut newest = (0, matrix.apply(&sources, true));
+      let amplitude = matrix.routes_to(ModDestination::Amplitude);
+      let width = pulse.width_at(self.pwm_phase);
+      let (min_width, max_width) = PULSE_WIDTH_RANGE;
+      let bin_hz = sample_rate / n as f32;
       self.window.fill(CZERO);
-      let mut fsum = 0.0;
-      for (i, b) in ss.iter_mut().enumerate() {
-        let s = if progress {
-          b.next(&self.control.adsr, dt, sustain)
-        } else {
-          b.peek(&self.control.adsr)
-        };
-        // let s = s / (i as f32 + 1.0) * 5.0;
-
-        fsum += s;
-        let v = Complex::new(0f32, s);
-        mode.calc(i, n, hn, v, &mut self.window);
+      let mut spectrum = Spectrum {
+        bins: &mut self.window,
+        window: self.synth_window,
+        gains: CZERO,
+        brightness: 1.0,
+        pulse_width: width,
+        partials: &params.partials,
+        table: &params.wavetable,
+        table_position: params.table_position,
+        noise,
+        noise_gain: self.noise_gain,
+        rng: &mut self.rng,
+      };
+      self.time.fill(CZERO);
+      let sample_dt = 1.0 / sample_rate;
+      let clock = self.clock;
+      let mut moving = 0;
+      for (((((voice, phases), started), age), fm_voices), mod_voice) in voices
+        .voices_mut()
+        .iter_mut()
+        .zip(self.phases.iter_mut())
+        .zip(self.started.iter_mut())
+        .zip(self.ages.iter_mut())
+        .zip(self.fm_voices.iter_mut())
+        .zip(self.mod_voices.iter_mut())
+      {
+        let layer = &layers[voice.layer];
+        let (mode, envelope) = (layer.mode, &layer.envelope);
+        let unison = unison.for_mode(mode);
+        if voice.started != *started {
+          *started = voice.started;
+          *age = 0.0;
+          if unison.random_phase && voice.state.peek(envelope) == 0.0 {
+            phases.iter_mut().for_each(|p| *p = spectrum.rng.phase());
+          }
+        }
+        let levels = &mut self.moving[moving].levels;
+        voice
+          .state
+          .render(envelope, sustain, &clock, commit, levels);
+        let mod_levels = &mut self.mod_levels;
+        let envelopes =
+          mod_voice.render(voice, &mod_envelopes, sustain, &clock, commit, mod_levels);
+        let sources = sources.voice(voice, levels[0] / envelope.peak(), envelopes);
+        // amplitude routes are followed sample by sample like the envelope
+        // itself, the other destinations only move once a hop
+        if amplitude {
+          let peak = envelope.peak();
+          for (k, (gain, level)) in self.mod_gains.iter_mut().zip(levels.iter_mut()).enumerate() {
+            let envelopes = std::array::from_fn(|e| mod_levels[e][k]);
+            *gain = matrix.gain(&sources.voice(voice, *level / peak, envelopes));
+            *level *= *gain;
+          }
+        }
+        let (s, tone_moves) = level_span(levels);
+        let modulation = matrix.apply(&sources, false);
+        if (tone_moves || s > 0.0) && voice.started > newest.0 {
+          newest = (voice.started, matrix.apply(&sources, true));
+        }
+        let cents = vibrato.cents(*age) + modulation.cents;
+        let freq = note_freq(voice.note) * pitch_ratio(bend, cents);
+        *age += step;
+        let bin = freq / bin_hz;
+        let gain = voice.gain * layer.volume;
+        if tone_moves || s > 0.0 {
+          let brightness = (voice.brightness + modulation.brightness).clamp(0.0, 1.0);
+          spectrum.brightness = brightness;
+          spectrum.pulse_width = (width + modulation.pulse_width).clamp(min_width, max_width);
+          if mode == NoteMode::Fm {
+            // the same scale a partial of `v` ends up with after the transform
+            let a = 2.0 * gain * unison.gain();
+            let levels = &self.moving[moving].levels;
+            for (k, fm_voice) in fm_voices.iter_mut().take(unison.count()).enumerate() {
+              let (ratio, offset) = unison.copy(k);
+              let (l, r) = params.pan_gains(voice.note, offset + modulation.pan);
+              let freq = freq * ratio * sample_dt;
+              for (t, level) in self.time.iter_mut().zip(levels.iter()) {
+                let x = a * level * fm_voice.next(voice, fm, freq, brightness, sample_dt, sustain);
+                *t += Complex::new(l * x, r * x);
+              }
+            }
+          } else {
+            // a moving level is rendered at full scale on its own and applied
+            // sample by sample after the transform
+            let own = tone_moves.then(|| {
+              moving += 1;
+              let bins = &mut self.moving[moving - 1].bins;
+              bins.fill(CZERO);
+              bins
+            });
+            let s = if tone_moves { 1.0 } else { s };
+            let v = Complex::new(0f32, s * self.gain * gain * unison.gain());
+            let add = |spectrum: &mut Spectrum| {
+              for (k, phase) in phases.iter().take(unison.count()).enumerate() {
+                let (ratio, offset) = unison.copy(k);
+                let (l, r) = params.pan_gains(voice.note, offset + modulation.pan);
+                spectrum.gains = Complex::new(l, r);
+                mode.calc(bin * ratio, v, *phase, spectrum);
+              }
+            };
+            match own {
+              Some(bins) => add(&mut spectrum.with_bins(bins)),
+              None => add(&mut spectrum),
+            }
+          }
+        }
+        let levels = &mut self.moving[moving].levels;
+        voice
+          .noise
+          .render(noise_envelope, sustain, &clock, commit, levels);
+        if amplitude {
+          let gains = self.mod_gains.iter();
+          levels
+            .iter_mut()
+            .zip(gains)
+            .for_each(|(level, gain)| *level *= gain);
+        }
+        let (ns, noise_moves) = level_span(levels);
+        let ns = ns * noise.layer;
+        if noise.layer > 0.0 && (noise_moves || ns > 0.0) {
+          let own = noise_moves.then(|| {
+            moving += 1;
+            let Moving { bins, levels } = &mut self.moving[moving - 1];
+            bins.fill(CZERO);
+            levels.iter_mut().for_each(|level| *level *= noise.layer);
+            bins
+          });
+          let ns = if noise_moves { 1.0 } else { ns };
+          let v = Complex::new(0f32, ns * self.gain * gain);
+          let (l, r) = params.pan_gains(voice.note, modulation.pan);
+          let add = |spectrum: &mut Spectrum| {
+            spectrum.gains = Complex::new(l, r);
+            let half = spectrum.half_len() as f32;
+            spectrum.add_noise(1.0, half, noise.color.slope(), v);
+          };
+          match own {
+            Some(bins) => add(&mut spectrum.with_bins(bins)),
+            None => add(&mut spectrum),
+          }
+        }
+        // keep every partial running across frames instead of restarting at zero
+        for (k, phase) in phases.iter_mut().take(unison.count()).enumerate() {
+          let bin = bin * unison.copy(k).0;
+          *phase = (*phase + TAU * bin * hop as f32 / n as f32) % TAU;
+        }
       }
-      if fsum > 1.0 {
-        for i in 1..n / 2 {
-          self.window[i + 1] /= fsum;
-          self.window[n - i - 1] /= fsum;
+      self.clock.advance(hop);
+      let moving = &mut self.moving[..moving];
+      if params.is_filtered() {
+        let filter = params.filter_at(&newest.1);
+        // a real gain on both halves filters the two channels alike
+        for k in 1..=n / 2 {
+          let gain = filter.gain(k as f32 * bin_hz);
+          let frames = moving.iter_mut().map(|m| &mut m.bins[..]);
+          for bins in std::iter::once(&mut self.window[..]).chain(frames) {
+            bins[k] *= gain;
+            if k != n - k {
+              bins[n - k] *= gain;
+            }
+          }
+        }
+        let coefs = SvfCoefs::new(&filter, sample_rate);
+        let [left, right] = &mut self.lowpass;
+        for t in self.time.iter_mut() {
+          *t = Complex::new(left.process(t.re, &coefs), right.process(t.im, &coefs));
         }
       }
       self
         .fft
         .process_with_scratch(&mut self.window, &mut self.buf);
 
+      self.out.copy_within(hop.., 0);
+      self.out[n - hop..].fill(CZERO);
+      for (o, c) in self.out.iter_mut().zip(self.window.iter()) {
+        *o += c;
+      }
+      for Moving { bins, levels } in moving.iter_mut() {
+        self.fft.process_with_scratch(bins, &mut self.buf);
+        for ((o, c), level) in self.out.iter_mut().zip(bins.iter()).zip(levels.iter()) {
+          *o += c * level;
+        }
+      }
+      // voices rendered in the time domain go straight to the next hop
+      for (o, c) in self.out.iter_mut().zip(self.time.iter()) {
+        *o += c;
+      }
       self.wp = 0;
     }
-    let v = self.window[self.wp].re;
+    let params = &self.audio.params;
+    let c = self.out[self.wp] * params.master;
     self.wp += 1;
-    v
+    let ([l, r], gain) = self.limiter.process([c.re, c.im], &params.limiter);
+    let control = self.audio.control();
+    control.meter(gain);
+    control.record((l + r) / 2.0);
+    self.right = Some(r);
+    l
   }
 }
 
@@ -274,7 +1393,7 @@ impl Iterator for Waves {
   type Item = f32;
 
   fn next(&mut self) -> Option<Self::Item> {
-    Some(self.calc(true))
+    Some(self.calc())
   }
 }
 impl Source for Waves {
@@ -283,15 +1402,155 @@ impl Source for Waves {
   }
 
   fn channels(&self) -> u16 {
-    1
+    2
   }
 
   fn sample_rate(&self) -> u32 {
-    // 44100
-    self.window.len() as u32 * 16
+    self.audio.control().sample_rate
   }
 
   fn total_duration(&self) -> Option<std::time::Duration> {
     None
   }
 }
+
+#[test]
+fn test_overlap() {
+  use SynthWindow::*;
+  for window in [Rectangular, Hann, Hamming, Blackman] {
+    assert!(window.overlaps_evenly(256, 1024), "{window:?}");
+    assert!(!window.overlaps_evenly(300, 1024), "{window:?}");
+  }
+  assert!(Rectangular.overlaps_evenly(1024, 1024));
+  assert!(!Hann.overlaps_evenly(1024, 1024));
+  assert!(Hann.overlaps_evenly(512, 1024));
+  assert!(!Blackman.overlaps_evenly(512, 1024));
+  // the sum itself is flat wherever the check passes
+  for window in [Hann, Blackman] {
+    for frames in 1..6 {
+      let (n, hop) = (60 * frames, 60);
+      let w = |k: usize| {
+        let x = TAU * (k % n) as f32 / n as f32;
+        let terms = window.terms().iter().enumerate();
+        terms
+          .map(|(m, a)| a * (m as f32 * x).cos() * if m % 2 == 0 { 1.0 } else { -1.0 })
+          .sum::<f32>()
+      };
+      let sums: Vec<f32> = (0..hop)
+        .map(|k| (0..frames).map(|j| w(k + j * hop)).sum())
+        .collect();
+      let flat = sums.iter().all(|s| (s - sums[0]).abs() < 1e-4);
+      assert_eq!(flat, window.overlaps_evenly(hop, n), "{window:?} {frames}");
+    }
+  }
+}
+
+#[test]
+fn test_builder_errors() {
+  use WavesBuilderError::*;
+  let error = |builder: WavesBuilder| builder.build().err();
+  assert_eq!(error(Waves::builder().sample_rate(0)), Some(ZeroSampleRate));
+  assert_eq!(
+    error(Waves::builder().sample_rate(10)),
+    Some(SampleRateTooLow(10))
+  );
+  assert_eq!(error(Waves::builder().frame_len(2)), Some(FrameTooShort(2)));
+  let hop = |hop| {
+    Waves::builder()
+      .frame_len(1024)
+      .synthesis(Synthesis::OverlapAdd {
+        hop,
+        window: SynthWindow::Hann,
+      })
+  };
+  for bad in [0, 2048] {
+    assert_eq!(
+      error(hop(bad)),
+      Some(InvalidHop {
+        hop: bad,
+        frame_len: 1024
+      })
+    );
+  }
+  assert_eq!(
+    error(hop(1024)),
+    Some(UnevenOverlap {
+      hop: 1024,
+      frame_len: 1024,
+      window: SynthWindow::Hann
+    })
+  );
+  assert_eq!(
+    error(Waves::builder().control_rate(0.0)),
+    Some(InvalidControlRate(0.0))
+  );
+  assert_eq!(
+    error(Waves::builder().sample_rate(8000).control_rate(9000.0)),
+    Some(ControlRateAboveSampleRate {
+      control_rate: 9000.0,
+      sample_rate: 8000
+    })
+  );
+  assert_eq!(
+    error(Waves::builder().synthesis(Synthesis::Block)),
+    Some(HopTooLong {
+      hop: 2048,
+      sample_rate: 44100
+    })
+  );
+  assert_eq!(error(Waves::builder().polyphony(0)), Some(ZeroPolyphony));
+  assert!(hop(256).build().is_ok());
+}
+
+#[test]
+fn test_mod_envelope_within_frame() {
+  use crate::modulation::ModSource;
+  // one block per frame, so a level stepped once a frame would hold still
+  let builder = Waves::builder().frame_len(1024);
+  let mut waves = builder.synthesis(Synthesis::Block).build().unwrap();
+  let control = waves.control();
+  let flat = EnvelopeParams::adsr(1.0, 0.001, 0.1, 1.0, 0.1).unwrap();
+  control.set_envelope(0, flat);
+  let blip = EnvelopeParams::adsr(1.0, 0.001, 0.005, 0.0, 0.1).unwrap();
+  control.set_mod_envelope(0, blip);
+  control.add_route(Route::new(
+    ModSource::Envelope(0),
+    ModDestination::Amplitude,
+    1.0,
+  ));
+  control.hit(57, 1.0);
+  // samples come out a frame late, interleaved left and right
+  let left: Vec<f32> = (0..4 * 1024).map(|_| waves.calc()).step_by(2).collect();
+  let frame = &left[1024..];
+  let peak = |samples: &[f32]| samples.iter().fold(0f32, |a, x| a.max(x.abs()));
+  // the blip rises and dies away inside the frame the note starts in
+  assert!(peak(&frame[..512]) > 0.5);
+  assert!(peak(&frame[512..]) < 0.01);
+  // a 440 Hz sine moves at most this much per sample at full level
+  let slope = TAU * 440.0 / 44100.0;
+  assert!(frame.windows(2).all(|w| (w[1] - w[0]).abs() < slope * 1.1));
+}
+
+#[test]
+fn test_vibrato_within_hop() {
+  let mut waves = Waves::builder().build().unwrap();
+  let control = waves.control();
+  control.set_mode(NoteMode::Sine);
+  control.set_vibrato(VibratoParams {
+    rate: 5.0,
+    depth: 100.0,
+    delay: 0.0,
+  });
+  control.hit(57, 1.0);
+  let left: Vec<f32> = (0..2 * 44100).map(|_| waves.calc()).step_by(2).collect();
+  // where each cycle starts, between samples
+  let starts: Vec<f32> = (20001..44100)
+    .filter(|&i| left[i - 1] < 0.0 && left[i] >= 0.0)
+    .map(|i| i as f32 - left[i] / (left[i] - left[i - 1]))
+    .collect();
+  let periods: Vec<f32> = starts.windows(2).map(|w| w[1] - w[0]).collect();
+  // the period swings smoothly, without a jump where one hop meets the next
+  for p in periods.windows(3) {
+    assert!(((p[2] - p[1]) - (p[1] - p[0])).abs() < 0.01 * p[1], "{p:?}");
+  }
+}
@@ -0,0 +1,294 @@
+use num::Complex;
+use std::{f32::consts::FRAC_PI_2, path::Path};
+
+/// Samples in every mipmap table.
+pub const TABLE_LEN: usize = 2048;
+/// Highest harmonic a table can hold.
+const MAX_HARMONICS: usize = TABLE_LEN / 2 - 1;
+
+#[derive(Debug)]
+pub enum WavetableError {
+  Io(std::io::Error),
+  NotWav,
+  /// Format tag and bits per sample of a WAV the reader can not decode.
+  Unsupported {
+    format: u16,
+    bits: u16,
+  },
+  /// There are no samples, or no frames of harmonics.
+  NoData,
+  /// The samples do not split into whole frames of the given length.
+  FrameLen {
+    len: usize,
+    frame_len: usize,
+  },
+}
+
+impl std::fmt::Display for WavetableError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      WavetableError::Io(e) => write!(f, "can not read wavetable: {e}"),
+      WavetableError::NotWav => write!(f, "not a RIFF WAVE file"),
+      WavetableError::Unsupported { format, bits } => {
+        write!(f, "unsupported WAV format {format} with {bits} bits")
+      }
+      WavetableError::NoData => write!(f, "no samples to build a wavetable from"),
+      WavetableError::FrameLen { len, frame_len } => {
+        write!(f, "{len} samples do not split into frames of {frame_len}")
+      }
+    }
+  }
+}
+
+impl std::error::Error for WavetableError {}
+
+impl From<std::io::Error> for WavetableError {
+  fn from(e: std::io::Error) -> Self {
+    WavetableError::Io(e)
+  }
+}
+
+/// Single-cycle frames to morph between, kept both as harmonics for the
+/// spectral engine and as band-limited tables for the time-domain one.
+#[derive(Debug, Clone)]
+pub struct Wavetable {
+  /// Per frame, harmonic `j` at index `j - 1` as `a * cis(p)`, standing for
+  /// `a * sin(j x + p)`.
+  harmonics: Vec<Box<[Complex<f32>]>>,
+  /// Per frame, one table per octave, each with half the harmonics of the last.
+  mipmaps: Vec<Vec<Box<[f32]>>>,
+}
+
+impl Wavetable {
+  /// Builds the tables from harmonics given as for [`Wavetable::harmonic`],
+  /// scaled together so the loudest frame peaks at one. There must be at
+  /// least one frame.
+  pub fn from_harmonics(frames: Vec<Vec<Complex<f32>>>) -> Result<Self, WavetableError> {
+    if frames.is_empty() {
+      return Err(WavetableError::NoData);
+    }
+    let mut planner = rustfft::FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_inverse(TABLE_LEN);
+    let mut harmonics: Vec<Box<[Complex<f32>]>> = frames
+      .into_iter()
+      .map(|mut h| {
+        h.truncate(MAX_HARMONICS);
+        h.into_boxed_slice()
+      })
+      .collect();
+    let mut mipmaps = vec![];
+    for frame in &harmonics {
+      let mut levels = vec![];
+      let mut limit = frame.len().max(1);
+      loop {
+        let mut buf = vec![Complex::new(0.0, 0.0); TABLE_LEN];
+        buf[1..=limit.min(frame.len())].copy_from_slice(&frame[..limit.min(frame.len())]);
+        fft.process(&mut buf);
+        // `a cis(p)` at bin `j` comes out as `a cis(j x + p)`, whose
+        // imaginary part is the sine we are after
+        levels.push(buf.iter().map(|c| c.im).collect::<Box<[f32]>>());
+        if limit == 1 {
+          break;
+        }
+        limit /= 2;
+      }
+      mipmaps.push(levels);
+    }
+    let peak = mipmaps
+      .iter()
+      .flat_map(|levels| levels[0].iter())
+      .fold(0f32, |a, b| a.max(b.abs()));
+    if peak > 0.0 {
+      for frame in &mut harmonics {
+        frame.iter_mut().for_each(|h| *h /= peak);
+      }
+      for level in mipmaps.iter_mut().flatten() {
+        level.iter_mut().for_each(|x| *x /= peak);
+      }
+    }
+    Ok(Self { harmonics, mipmaps })
+  }
+  /// Frames of `frame_len` samples each, one cycle per frame.
+  pub fn from_samples(samples: &[f32], frame_len: usize) -> Result<Self, WavetableError> {
+    if samples.is_empty() {
+      return Err(WavetableError::NoData);
+    }
+    if frame_len < 2 || !samples.len().is_multiple_of(frame_len) {
+      return Err(WavetableError::FrameLen {
+        len: samples.len(),
+        frame_len,
+      });
+    }
+    let mut planner = rustfft::FftPlanner::<f32>::new();
+    let fft = planner.plan_fft_forward(frame_len);
+    let frames = samples
+      .chunks(frame_len)
+      .map(|frame| {
+        let mut buf: Vec<_> = frame.iter().map(|&x| Complex::new(x, 0.0)).collect();
+        fft.process(&mut buf);
+        // bin `j` of a real cycle holds `a/2 cis(p)` of `a cos(j x + p)`,
+        // which is `a sin(j x + p + PI/2)`
+        let scale = 2.0 / frame_len as f32;
+        buf[1..frame_len.div_ceil(2)]
+          .iter()
+          .map(|c| c * scale * Complex::cis(FRAC_PI_2))
+          .collect()
+      })
+      .collect();
+    Self::from_harmonics(frames)
+  }
+  /// Reads a WAV holding one cycle, or several back to back when `frame_len`
+  /// is given.
+  pub fn from_wav(bytes: &[u8], frame_len: Option<usize>) -> Result<Self, WavetableError> {
+    let samples = read_wav(bytes)?;
+    Self::from_samples(&samples, frame_len.unwrap_or(samples.len()))
+  }
+  pub fn load(path: impl AsRef<Path>, frame_len: Option<usize>) -> Result<Self, WavetableError> {
+    Self::from_wav(&std::fs::read(path)?, frame_len)
+  }
+  /// Sine, triangle, saw and square, to morph through until a table is loaded.
+  pub fn basic() -> Self {
+    let series = |f: fn(f32) -> f32| {
+      (1..=MAX_HARMONICS)
+        .map(|j| Complex::new(f(j as f32), 0.0))
+        .collect()
+    };
+    fn odd(j: f32) -> bool {
+      j % 2.0 == 1.0
+    }
+    Self::from_harmonics(vec![
+      series(|j| if j == 1.0 { 1.0 } else { 0.0 }),
+      series(|j| {
+        if odd(j) {
+          (-1f32).powi(j as i32 / 2) / (j * j)
+        } else {
+          0.0
+        }
+      }),
+      series(|j| 1.0 / j),
+      series(|j| if odd(j) { 1.0 / j } else { 0.0 }),
+    ])
+    .expect("four frames")
+  }
+  pub fn frame_count(&self) -> usize {
+    self.harmonics.len()
+  }
+  /// Frames on either side of `position` (0 to 1) and how far it is between them.
+  fn frames(&self, position: f32) -> (usize, usize, f32) {
+    let last = self.frame_count().saturating_sub(1);
+    let x = position.clamp(0.0, 1.0) * last as f32;
+    let a = (x.floor() as usize).min(last);
+    (a, (a + 1).min(last), x - a as f32)
+  }
+  /// Harmonic `j` at `position`, zero past the end of the table.
+  pub fn harmonic(&self, position: f32, j: usize) -> Complex<f32> {
+    let (a, b, f) = self.frames(position);
+    let get = |frame: usize| {
+      self.harmonics[frame]
+        .get(j - 1)
+        .copied()
+        .unwrap_or_default()
+    };
+    get(a) * (1.0 - f) + get(b) * f
+  }
+  /// Highest harmonic any frame has.
+  pub fn harmonic_count(&self) -> usize {
+    self.harmonics.iter().map(|h| h.len()).max().unwrap_or(0)
+  }
+  /// Sample at phase `t` (in cycles) advancing by `dt` per sample, from the
+  /// tables with as many harmonics as fit below Nyquist.
+  pub fn sample(&self, position: f32, t: f32, dt: f32) -> f32 {
+    let (a, b, f) = self.frames(position);
+    let fits = (0.5 / dt.max(f32::EPSILON)) as usize;
+    let lookup = |frame: usize| {
+      let levels = &self.mipmaps[frame];
+      let top = self.harmonics[frame].len().max(1);
+      let level = (0..levels.len())
+        .find(|&k| top >> k <= fits)
+        .unwrap_or(levels.len() - 1);
+      let table = &levels[level];
+      let x = t * TABLE_LEN as f32;
+      let i = x as usize % TABLE_LEN;
+      let frac = x.fract();
+      table[i] * (1.0 - frac) + table[(i + 1) % TABLE_LEN] * frac
+    };
+    let x = lookup(a);
+    if f > 0.0 {
+      x * (1.0 - f) + lookup(b) * f
+    } else {
+      x
+    }
+  }
+}
+
+/// Decodes a PCM or float WAV, mixed down to mono.
+pub fn read_wav(bytes: &[u8]) -> Result<Vec<f32>, WavetableError> {
+  if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+    return Err(WavetableError::NotWav);
+  }
+  let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+  let mut format = None;
+  let mut data = None;
+  let mut pos = 12;
+  while pos + 8 <= bytes.len() {
+    let id = &bytes[pos..pos + 4];
+    let size = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+    let body = &bytes[pos + 8..(pos + 8).saturating_add(size).min(bytes.len())];
+    match id {
+      b"fmt " if body.len() >= 16 => {
+        let mut tag = u16_at(body, 0);
+        // WAVE_FORMAT_EXTENSIBLE keeps the real tag in its sub-format
+        if tag == 0xFFFE && body.len() >= 26 {
+          tag = u16_at(body, 24);
+        }
+        format = Some((tag, u16_at(body, 2).max(1) as usize, u16_at(body, 14)));
+      }
+      b"data" => data = Some(body),
+      _ => (),
+    }
+    // chunks are padded to an even length
+    pos += 8 + size + (size & 1);
+  }
+  let (Some((tag, channels, bits)), Some(data)) = (format, data) else {
+    return Err(WavetableError::NoData);
+  };
+  let width = bits as usize / 8;
+  let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+    (1, 8) => |b| (b[0] as f32 - 128.0) / 128.0,
+    (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+    (1, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2147483648.0,
+    (1, 32) => |b| i32::from_le_bytes(b.try_into().unwrap()) as f32 / 2147483648.0,
+    (3, 32) => |b| f32::from_le_bytes(b.try_into().unwrap()),
+    (3, 64) => |b| f64::from_le_bytes(b.try_into().unwrap()) as f32,
+    (format, bits) => return Err(WavetableError::Unsupported { format, bits }),
+  };
+  let samples: Vec<f32> = data
+    .chunks_exact(width * channels)
+    .map(|frame| frame.chunks_exact(width).map(decode).sum::<f32>() / channels as f32)
+    .collect();
+  if samples.is_empty() {
+    return Err(WavetableError::NoData);
+  }
+  Ok(samples)
+}
+
+#[test]
+fn test_read_wav() {
+  let mut wav = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+  wav.extend(16u32.to_le_bytes());
+  // PCM, stereo, 44100 Hz, 176400 bytes/s, 4 byte frames, 16 bits
+  for field in [1u16, 2, 44100, 0, 0xB110, 0x2, 4, 16] {
+    wav.extend(field.to_le_bytes());
+  }
+  wav.extend(b"data");
+  wav.extend(8u32.to_le_bytes());
+  for sample in [16384i16, 0, -32768, -32768] {
+    wav.extend(sample.to_le_bytes());
+  }
+  assert_eq!(read_wav(&wav).unwrap(), [0.25, -1.0]);
+  assert!(matches!(read_wav(b"RIFX"), Err(WavetableError::NotWav)));
+  assert!(matches!(
+    Wavetable::from_harmonics(vec![]),
+    Err(WavetableError::NoData)
+  ));
+}
@@ -1,13 +1,12 @@
+use crate::envelope::{Breakpoint, MAX_STAGE_SECS};
+use crate::fm::FmAlgorithm;
+use crate::noise::NoiseColor;
+use crate::preset::Preset;
+use crate::voices::MAX_LAYERS;
 use crate::waves::{NoteMode, WavesControl};
 use cutils::csizeof;
 use plotters::{backend::BGRXPixel, prelude::*};
-use std::{
-  cell::UnsafeCell,
-  ffi::OsStr,
-  os::windows::prelude::OsStrExt,
-  ptr::null_mut,
-  sync::{atomic::Ordering, Arc},
-};
+use std::{cell::UnsafeCell, ffi::OsStr, os::windows::prelude::OsStrExt, ptr::null_mut, sync::Arc};
 use winapi::{
   shared::{
     minwindef::{HINSTANCE, LPARAM, LRESULT, UINT, WPARAM},
@@ -20,18 +19,50 @@ use winapi::{
       WHITE_BRUSH,
     },
     winuser::{
-      CreateWindowExW, DefWindowProcW, DispatchMessageW, GetClientRect, GetDC, LoadCursorW,
-      PeekMessageW, PostQuitMessage, RegisterClassExW, ReleaseDC, ShowWindow, CS_DBLCLKS,
-      CS_HREDRAW, CS_VREDRAW, IDC_ARROW, MK_CONTROL, MK_LBUTTON, MK_MBUTTON, MK_RBUTTON, MK_SHIFT,
-      MK_XBUTTON1, MK_XBUTTON2, MSG, PM_REMOVE, SW_SHOWMAXIMIZED, VK_OEM_1, VK_OEM_4, VK_OEM_5,
-      VK_OEM_6, VK_OEM_7, WM_DESTROY, WM_KEYDOWN, WM_KEYUP, WM_MOUSEFIRST, WM_MOUSELAST, WM_QUIT,
-      WNDCLASSEXW, WS_OVERLAPPEDWINDOW,
+      CreateWindowExW, DefWindowProcW, DispatchMessageW, GetClientRect, GetDC, GetKeyState,
+      LoadCursorW, PeekMessageW, PostQuitMessage, RegisterClassExW, ReleaseDC, ShowWindow,
+      CS_DBLCLKS, CS_HREDRAW, CS_VREDRAW, IDC_ARROW, MK_CONTROL, MK_LBUTTON, MK_MBUTTON,
+      MK_RBUTTON, MK_SHIFT, MK_XBUTTON1, MK_XBUTTON2, MSG, PM_REMOVE, SW_SHOWMAXIMIZED, VK_DELETE,
+      VK_DOWN, VK_F2, VK_F3, VK_F5, VK_F6, VK_F7, VK_F8, VK_F9, VK_LEFT, VK_OEM_1, VK_OEM_2,
+      VK_OEM_4, VK_OEM_5, VK_OEM_6, VK_OEM_7, VK_OEM_COMMA, VK_OEM_MINUS, VK_OEM_PERIOD,
+      VK_OEM_PLUS, VK_RIGHT, VK_SHIFT, VK_TAB, VK_UP, WHEEL_DELTA, WM_DESTROY, WM_KEYDOWN,
+      WM_KEYUP, WM_MOUSEFIRST, WM_MOUSELAST, WM_MOUSEWHEEL, WM_QUIT, WNDCLASSEXW,
+      WS_OVERLAPPEDWINDOW,
     },
   },
 };
 
+/// Note played by the first sound key (C3, counting semitones up from C0).
+const BASE_NOTE: usize = 36;
+/// Velocity of a sound key, the top so that velocity curves leave it at full
+/// level, and of a softer one pressed together with shift.
+const KEY_VELOCITY: f32 = 1.0;
+const SOFT_VELOCITY: f32 = 0.6;
+/// How much `5` narrows and `6` widens the pulse.
+const PULSE_WIDTH_STEP: f32 = 0.05;
+/// How far `.` and `/` move through the wavetable.
+const TABLE_POSITION_STEP: f32 = 1.0 / 16.0;
+/// Level of the noise layer when `0` turns it on.
+const NOISE_LAYER: f32 = 0.3;
+/// Share of the bend range one notch of the mouse wheel moves.
+const BEND_WHEEL_STEP: f32 = 1.0 / 8.0;
+/// First note of the second layer while F3 splits the keyboard: the top
+/// row of sound keys plays the first layer, the row below the second.
+const SPLIT_NOTE: usize = BASE_NOTE + 12;
+/// Where F5 saves the sound and F9 loads it back from.
+const PRESET_PATH: &str = "preset.txt";
+/// How much F7 shrinks and F8 grows the depth of the route picked with F6.
+const ROUTE_DEPTH_FACTOR: f32 = 1.25;
+/// Shortest first or last segment left and right (with shift) can halve
+/// down to.
+const MIN_STAGE_SECS: f32 = 0.001;
+
 pub struct WindowState {
   pub mouse: MouseMoveEvent,
+  /// Layer the mode and envelope keys change, picked with tab.
+  pub layer: usize,
+  /// Route of the modulation matrix F7, F8 and delete change, picked with F6.
+  pub route: usize,
 }
 pub struct WindowUpdater(WindowBackend, pub WindowState);
 pub struct WindowBackend(Arc<UnsafeCell<WindowBackendInner>>);
@@ -71,6 +102,8 @@ impl WindowBackend {
         Self(Arc::clone(&self.0)),
         WindowState {
           mouse: Default::default(),
+          layer: 0,
+          route: 0,
         },
       ),
       BitMapBackend::with_buffer_and_format(inner.bm_buffer.as_mut_slice(), size).unwrap(),
@@ -114,7 +147,11 @@ impl WindowUpdater {
         &inner.sound_key_vks,
       ) {
         if pressed {
-          inner.control.hit(i);
+          let shift = unsafe { GetKeyState(VK_SHIFT) } < 0;
+          let velocity = if shift { SOFT_VELOCITY } else { KEY_VELOCITY };
+          inner.control.hit(BASE_NOTE + i, velocity);
+        } else {
+          inner.control.release(BASE_NOTE + i);
         }
         continue;
       }
@@ -127,27 +164,160 @@ impl WindowUpdater {
         let code = inner.msg.wParam as u8;
         match code {
           b' ' => {
-            inner.control.sustain.store(pressed, Ordering::Relaxed);
+            inner.control.set_pedal(pressed);
           }
-          b'1' | b'2' | b'3' | b'4' if pressed => {
+          b'1' | b'2' | b'3' | b'4' | b'7' | b'8' if pressed => {
             let mode = match code {
               b'1' => NoteMode::Sine,
               b'2' => NoteMode::Saw,
               b'3' => NoteMode::Square,
               b'4' => NoteMode::Triangle,
+              b'7' => NoteMode::Custom,
+              b'8' => NoteMode::Noise,
               _ => unreachable!(),
             };
-            unsafe { *inner.control.mode.get() = mode };
+            set_mode(&inner.control, self.1.layer, mode);
+          }
+          c if c == VK_OEM_MINUS as u8 && pressed => {
+            set_mode(&inner.control, self.1.layer, NoteMode::Fm);
+          }
+          c if c == VK_OEM_PLUS as u8 && pressed => {
+            let mut fm = inner.control.fm();
+            let i = FmAlgorithm::ALL.iter().position(|a| *a == fm.algorithm);
+            fm.algorithm = FmAlgorithm::ALL[i.map_or(0, |i| (i + 1) % FmAlgorithm::ALL.len())];
+            inner.control.set_fm(fm);
+          }
+          c if c == VK_OEM_COMMA as u8 && pressed => {
+            set_mode(&inner.control, self.1.layer, NoteMode::Wavetable);
+          }
+          c if (c == VK_OEM_PERIOD as u8 || c == VK_OEM_2 as u8) && pressed => {
+            let step = if c == VK_OEM_PERIOD as u8 {
+              -TABLE_POSITION_STEP
+            } else {
+              TABLE_POSITION_STEP
+            };
+            let position = inner.control.table_position();
+            inner.control.set_table_position(position + step);
+          }
+          b'9' if pressed => {
+            let mut noise = inner.control.noise();
+            noise.color = match noise.color {
+              NoiseColor::White => NoiseColor::Pink,
+              NoiseColor::Pink => NoiseColor::Brown,
+              NoiseColor::Brown => NoiseColor::White,
+            };
+            inner.control.set_noise(noise);
+          }
+          b'0' if pressed => {
+            let mut noise = inner.control.noise();
+            noise.layer = if noise.layer > 0.0 { 0.0 } else { NOISE_LAYER };
+            inner.control.set_noise(noise);
+          }
+          // held like a spring-loaded wheel: fully bent while down, back on release
+          c if c == VK_UP as u8 || c == VK_DOWN as u8 => {
+            let amount = match (pressed, c == VK_UP as u8) {
+              (false, _) => 0.0,
+              (true, true) => 1.0,
+              (true, false) => -1.0,
+            };
+            inner.control.set_bend(amount);
+          }
+          b'5' | b'6' if pressed => {
+            let step = if code == b'5' {
+              -PULSE_WIDTH_STEP
+            } else {
+              PULSE_WIDTH_STEP
+            };
+            let width = inner.control.pulse_width();
+            inner.control.set_pulse_width(width + step);
           }
+          c if (c == VK_LEFT as u8 || c == VK_RIGHT as u8) && pressed => {
+            let factor = if c == VK_LEFT as u8 { 0.5 } else { 2.0 };
+            let shift = unsafe { GetKeyState(VK_SHIFT) } < 0;
+            // the attack, or with shift the release
+            let envelope = inner.control.layers()[self.1.layer].envelope;
+            let index = if shift {
+              envelope.points().len() - 1
+            } else {
+              0
+            };
+            let point = envelope.points()[index];
+            let time = (point.time * factor).clamp(MIN_STAGE_SECS, MAX_STAGE_SECS);
+            match envelope.with_point(index, Breakpoint { time, ..point }) {
+              Ok(envelope) => inner.control.set_envelope(self.1.layer, envelope),
+              Err(e) => eprintln!("{e}"),
+            }
+          }
+          c if c == VK_TAB as u8 && pressed => {
+            self.1.layer = (self.1.layer + 1) % MAX_LAYERS;
+          }
+          c if c == VK_F2 as u8 && pressed => {
+            let mut layer = inner.control.layers()[self.1.layer];
+            layer.enabled = !layer.enabled;
+            inner.control.set_layer(self.1.layer, layer);
+          }
+          c if c == VK_F3 as u8 && pressed => {
+            let split = inner.control.layers()[1].keys.0 > 0;
+            inner.control.set_split((!split).then_some(SPLIT_NOTE));
+          }
+          c if c == VK_F6 as u8 && pressed => {
+            let count = inner.control.mod_matrix().routes.len();
+            self.1.route = (self.1.route + 1) % count.max(1);
+          }
+          c if (c == VK_F7 as u8 || c == VK_F8 as u8) && pressed => {
+            let factor = if c == VK_F7 as u8 {
+              1.0 / ROUTE_DEPTH_FACTOR
+            } else {
+              ROUTE_DEPTH_FACTOR
+            };
+            let route = inner.control.mod_matrix().routes.get(self.1.route).copied();
+            if let Some(route) = route {
+              inner
+                .control
+                .set_route_depth(self.1.route, route.depth * factor);
+            }
+          }
+          c if c == VK_DELETE as u8 && pressed => {
+            inner.control.remove_route(self.1.route);
+            let count = inner.control.mod_matrix().routes.len();
+            self.1.route = self.1.route.min(count.saturating_sub(1));
+          }
+          c if c == VK_F5 as u8 && pressed => {
+            if let Err(e) = Preset::capture(&inner.control).save(PRESET_PATH) {
+              eprintln!("{PRESET_PATH}: {e}");
+            }
+          }
+          c if c == VK_F9 as u8 && pressed => match Preset::load(PRESET_PATH) {
+            Ok(preset) => preset.apply(&inner.control),
+            Err(e) => eprintln!("{PRESET_PATH}: {e}"),
+          },
           _ => (),
         }
         continue;
       }
+      if inner.msg.message == WM_MOUSEWHEEL {
+        let notches = (inner.msg.wParam >> 16) as i16 as f32 / WHEEL_DELTA as f32;
+        let amount = inner.control.bend().amount;
+        inner.control.set_bend(amount + notches * BEND_WHEEL_STEP);
+        continue;
+      }
       if let Some(event) = process_mouse(
         inner.msg.message,
         inner.msg.wParam,
         inner.msg.lParam as usize,
       ) {
+        let mut client_rect = RECT {
+          left: 0,
+          top: 0,
+          right: 0,
+          bottom: 0,
+        };
+        unsafe { GetClientRect(inner.hwnd, &mut client_rect) };
+        let width = (client_rect.right - client_rect.left).max(1) as f32;
+        let height = (client_rect.bottom - client_rect.top).max(1) as f32;
+        inner
+          .control
+          .set_mouse(event.x as f32 / width, 1.0 - event.y as f32 / height);
         self.1.mouse = event;
       }
     }
@@ -182,7 +352,27 @@ impl WindowBackendInner {
     let sound_key_states = vec![false; sound_key_vks.len()];
     let special_key_vks = {
       let mut key_vks = vec![];
-      key_vks.extend(b" 1234".map(|c| c as i32));
+      key_vks.extend(b" 1234567890".map(|c| c as i32));
+      key_vks.extend([
+        VK_OEM_MINUS,
+        VK_OEM_PLUS,
+        VK_OEM_COMMA,
+        VK_OEM_PERIOD,
+        VK_OEM_2,
+        VK_UP,
+        VK_DOWN,
+        VK_LEFT,
+        VK_RIGHT,
+        VK_TAB,
+        VK_F2,
+        VK_F3,
+        VK_F5,
+        VK_F6,
+        VK_F7,
+        VK_F8,
+        VK_F9,
+        VK_DELETE,
+      ]);
       key_vks
     };
     let special_key_states = vec![false; special_key_vks.len()];
@@ -245,6 +435,14 @@ impl WindowBackendInner {
   }
 }
 
+/// Sets the mode of `layer`, turning it on if it was off.
+fn set_mode(control: &WavesControl, index: usize, mode: NoteMode) {
+  let mut layer = control.layers()[index];
+  layer.enabled = true;
+  layer.mode = mode;
+  control.set_layer(index, layer);
+}
+
 fn process_keyboard(
   message: u32,
   key: i32,